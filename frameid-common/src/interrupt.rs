@@ -0,0 +1,30 @@
+use ctrlc;
+
+use failure::Error;
+
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Interrupted")]
+pub struct Interrupted;
+
+/// Turns SIGINT and SIGTERM into a request to finish early. Running
+/// pipelines notice it and send EOS, so the muxers still finalise their
+/// output. A second signal exits right away.
+pub fn install() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+        eprintln!("Interrupted, finishing the output (interrupt again to abort)");
+    })?;
+
+    Ok(())
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
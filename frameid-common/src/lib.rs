@@ -0,0 +1,12 @@
+//! What video-frameid-prepare and video-align both need from the command
+//! line: taking inputs as paths or URIs, and finishing early on ctrl-c.
+
+extern crate ctrlc;
+extern crate glib;
+
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
+
+pub mod interrupt;
+pub mod uri;
@@ -0,0 +1,50 @@
+use glib;
+
+use std::fs;
+
+/// Accepts both URIs and plain (possibly relative) file paths
+pub fn to_uri(location: &str) -> Result<String, String> {
+    if location.contains("://") {
+        return Ok(location.to_owned());
+    }
+
+    let path = fs::canonicalize(location).map_err(|e| format!("{}: {}", location, e))?;
+    glib::filename_to_uri(&path, None).map_err(|e| format!("{}: {}", location, e))
+}
+
+/// Outputs are always written locally, so file:// URIs are turned back into paths
+pub fn to_path(location: &str) -> Result<String, String> {
+    if !location.contains("://") {
+        return Ok(location.to_owned());
+    }
+
+    glib::filename_from_uri(location)
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+        .map_err(|e| format!("{}: {}", location, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_are_kept() {
+        assert_eq!(to_uri("rtsp://127.0.0.1:8554/test").unwrap(), "rtsp://127.0.0.1:8554/test");
+        assert_eq!(to_path("output.mp4").unwrap(), "output.mp4");
+    }
+
+    #[test]
+    fn relative_paths_become_file_uris() {
+        // Tests run from the crate's directory
+        let uri = to_uri("Cargo.toml").unwrap();
+        assert!(uri.starts_with("file:///") && uri.ends_with("/frameid-common/Cargo.toml"), "{}", uri);
+
+        let path = fs::canonicalize("Cargo.toml").unwrap();
+        assert_eq!(to_path(&uri).unwrap(), path.to_string_lossy());
+    }
+
+    #[test]
+    fn missing_paths_are_errors() {
+        assert!(to_uri("missing.mp4").unwrap_err().starts_with("missing.mp4: "));
+    }
+}
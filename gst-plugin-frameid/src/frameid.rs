@@ -12,13 +12,14 @@ use std::{i32, u64};
 use std::sync::Mutex;
 
 use qrcode::QrCode;
-use image::Luma;
+use image::{GrayImage, Luma};
 
 #[derive(Debug, Clone)]
 struct Settings {
     pub prefix: Option<String>,
     pub position: Option<String>,
     pub quiet_zone: bool,
+    pub first_index: u64,
 }
 
 impl Default for Settings {
@@ -26,7 +27,84 @@ impl Default for Settings {
         Settings {
             prefix: None,
             position: Some("top-left".to_owned()),
-            quiet_zone: true
+            quiet_zone: true,
+            first_index: 0,
+        }
+    }
+}
+
+/// Transfer function of the video, which decides how bright the white of
+/// the qrcode is drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transfer {
+    Sdr,
+    /// SMPTE ST 2084, HDR10
+    Pq,
+    /// ARIB STD-B67
+    Hlg,
+}
+
+impl Transfer {
+    /// Colorimetry is either a name (bt2100-pq) or range:matrix:transfer:primaries
+    fn from_colorimetry(colorimetry: &str) -> Transfer {
+        let colorimetry = colorimetry.to_lowercase();
+        let transfer = colorimetry.split(':').nth(2);
+        if colorimetry.ends_with("-pq") || transfer == Some("14") {
+            Transfer::Pq
+        } else if colorimetry.ends_with("-hlg") || transfer == Some("15") {
+            Transfer::Hlg
+        } else {
+            Transfer::Sdr
+        }
+    }
+
+    /// Signal level of the white, relative to the nominal range. HDR uses the
+    /// reference white of ITU-R BT.2408 rather than the peak, which would be
+    /// blinding and tone mapped differently by every display.
+    fn white(&self) -> f64 {
+        match *self {
+            Transfer::Sdr => 1.0,
+            Transfer::Pq => 0.58,
+            Transfer::Hlg => 0.75,
+        }
+    }
+}
+
+/// Code values the qrcode is drawn with
+#[derive(Debug, Clone, Copy)]
+struct Levels {
+    black: u16,
+    white: u16,
+    /// Chroma of a grey
+    neutral: u16,
+}
+
+impl Levels {
+    fn new(info: &gst_video::VideoInfo, caps: &gst::Caps) -> Levels {
+        let colorimetry = caps.get_structure(0).and_then(|s| s.get::<String>("colorimetry"));
+        let transfer = colorimetry.as_ref().map_or(Transfer::Sdr, |c| Transfer::from_colorimetry(c));
+        let depth = match info.format() {
+            gst_video::VideoFormat::I42010le | gst_video::VideoFormat::P01010le => 10,
+            _ => 8,
+        };
+
+        // RGB is always full range, YUV is limited range unless the
+        // colorimetry says otherwise
+        let full_range = match colorimetry {
+            Some(ref c) if c.contains(':') => c.starts_with("1:"),
+            Some(ref c) => c.to_lowercase() == "srgb",
+            None => info.format() == gst_video::VideoFormat::Rgbx,
+        };
+        let (black, range) = if full_range {
+            (0, (1 << depth) - 1)
+        } else {
+            (16 << (depth - 8), 219 << (depth - 8))
+        };
+
+        Levels {
+            black: black as u16,
+            white: (black as f64 + range as f64 * transfer.white()).round() as u16,
+            neutral: 1 << (depth - 1),
         }
     }
 }
@@ -34,6 +112,51 @@ impl Default for Settings {
 struct State {
     frame_index: u64,
     info: gst_video::VideoInfo,
+    levels: Levels,
+}
+
+/// Writes a sample of `bytes` little endian bytes
+fn put_sample(data: &mut [u8], index: usize, bytes: usize, value: u16) {
+    data[index] = (value & 0xff) as u8;
+    if bytes == 2 {
+        data[index + 1] = (value >> 8) as u8;
+    }
+}
+
+/// Draws `image` at `offsets` into a YUV 4:2:0 frame with samples of
+/// `bytes` bytes, shifted left by `shift` bits (P010 keeps its 10 bits at the
+/// top). The chroma under the qrcode is made neutral so it stays grey.
+fn draw_yuv(data: &mut [u8], info: &gst_video::VideoInfo, image: &GrayImage, offsets: (u32, u32),
+            levels: &Levels, bytes: usize, shift: u32, interleaved_chroma: bool) {
+    let stride = info.stride();
+    let offset = info.offset();
+    let (width, height) = image.dimensions();
+
+    for y in 0..height {
+        for x in 0..width {
+            let value = if image.get_pixel(x, y)[0] > 127 { levels.white } else { levels.black };
+            let index = offset[0] + (offsets.1 + y) as usize * stride[0] as usize + (offsets.0 + x) as usize * bytes;
+            put_sample(data, index, bytes, value << shift);
+        }
+    }
+
+    let neutral = levels.neutral << shift;
+    let chroma_width = (info.width() + 1) / 2;
+    let chroma_height = (info.height() + 1) / 2;
+    for y in offsets.1 / 2..((offsets.1 + height + 1) / 2).min(chroma_height) {
+        for x in offsets.0 / 2..((offsets.0 + width + 1) / 2).min(chroma_width) {
+            if interleaved_chroma {
+                let index = offset[1] + y as usize * stride[1] as usize + x as usize * 2 * bytes;
+                put_sample(data, index, bytes, neutral);
+                put_sample(data, index + bytes, bytes, neutral);
+            } else {
+                for plane in 1..3 {
+                    let index = offset[plane] + y as usize * stride[plane] as usize + x as usize * bytes;
+                    put_sample(data, index, bytes, neutral);
+                }
+            }
+        }
+    }
 }
 
 struct FrameId {
@@ -42,7 +165,7 @@ struct FrameId {
     state: Mutex<Option<State>>,
 }
 
-static PROPERTIES: [Property; 3] = [
+static PROPERTIES: [Property; 4] = [
     Property::String(
         "prefix",
         "Prefix to add to frame index",
@@ -63,7 +186,15 @@ static PROPERTIES: [Property; 3] = [
         "If a quiet white border should be drawn around the qrcode",
         true,
         PropertyMutability::ReadWrite
-    )
+    ),
+    Property::UInt64(
+        "first-index",
+        "Index of the first frame",
+        "Frame index written on the first frame after caps are set",
+        (0, u64::MAX),
+        0,
+        PropertyMutability::ReadWrite
+    ),
 ];
 
 impl FrameId {
@@ -94,6 +225,9 @@ impl FrameId {
                     "format",
                     &gst::List::new(&[
                         &gst_video::VideoFormat::Rgbx.to_string(),
+                        &gst_video::VideoFormat::I420.to_string(),
+                        &gst_video::VideoFormat::I42010le.to_string(),
+                        &gst_video::VideoFormat::P01010le.to_string(),
                     ]),
                 ),
                 ("width", &gst::IntRange::<i32>::new(0, i32::MAX)),
@@ -149,6 +283,10 @@ impl ObjectImpl<BaseTransform> for FrameId {
                     None => settings.quiet_zone = true
                 }
             }
+            Property::UInt64("first-index", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.first_index = value.get().unwrap_or(0);
+            }
             _ => unimplemented!(),
         }
     }
@@ -169,6 +307,10 @@ impl ObjectImpl<BaseTransform> for FrameId {
                 let settings = self.settings.lock().unwrap();
                 Ok(settings.quiet_zone.to_value())
             }
+            Property::UInt64("first-index", ..) => {
+                let settings = self.settings.lock().unwrap();
+                Ok(settings.first_index.to_value())
+            }
             _ => unimplemented!(),
         }
     }
@@ -177,15 +319,21 @@ impl ObjectImpl<BaseTransform> for FrameId {
 impl ElementImpl<BaseTransform> for FrameId {}
 
 impl BaseTransformImpl<BaseTransform> for FrameId {
-    fn transform_ip(&self, _element: &BaseTransform, buf: &mut gst::BufferRef) -> gst::FlowReturn {
+    fn transform_ip(&self, element: &BaseTransform, buf: &mut gst::BufferRef) -> gst::FlowReturn {
         let mut state_guard = self.state.lock().unwrap();
         let state = match *state_guard {
-            None => return gst::FlowReturn::NotNegotiated,
+            None => {
+                gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                return gst::FlowReturn::NotNegotiated;
+            }
             Some(ref mut state) => state,
         };
 
         let mut map = match buf.map_writable() {
-            None => return gst::FlowReturn::Error,
+            None => {
+                gst_element_error!(element, gst::CoreError::Failed, ["Failed to map buffer writable"]);
+                return gst::FlowReturn::Error;
+            }
             Some(map) => map,
         };
 
@@ -195,9 +343,29 @@ impl BaseTransformImpl<BaseTransform> for FrameId {
             Some(ref a) => a.clone(),
         };
         text.push_str(&state.frame_index.to_string());
-        let code = QrCode::new(text.to_string()).unwrap();
+        let code = match QrCode::new(text.as_bytes()) {
+            Ok(code) => code,
+            Err(err) => {
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Encode,
+                    ["Failed to generate qrcode for '{}': {:?}", text, err]
+                );
+                return gst::FlowReturn::Error;
+            }
+        };
         let image = code.render::<Luma<u8>>().quiet_zone(true).build();
 
+        if image.width() > state.info.width() || image.height() > state.info.height() {
+            gst_element_error!(
+                element,
+                gst::StreamError::Format,
+                ["qrcode of {}x{} does not fit in a {}x{} frame",
+                 image.width(), image.height(), state.info.width(), state.info.height()]
+            );
+            return gst::FlowReturn::Error;
+        }
+
         let offsets = match settings.position.as_ref().map(String::as_ref) {
             Some("top-right") => (state.info.width() - image.width(), 0),
             Some("bottom-left") => (0, state.info.height() - image.height()),
@@ -205,14 +373,26 @@ impl BaseTransformImpl<BaseTransform> for FrameId {
             _ => (0, 0),
         };
 
-        let dimensions = image.dimensions();
-        for y in 0..dimensions.1 {
-            for x in 0..dimensions.0 {
-                let baseindex : usize = 4 * (offsets.0 + x + state.info.width() * (offsets.1 + y)) as usize;
-                let pixel = image.get_pixel(x, y);
-                map.as_mut_slice()[baseindex] = pixel[0];
-                map.as_mut_slice()[baseindex + 1] = pixel[0];
-                map.as_mut_slice()[baseindex + 2] = pixel[0];
+        // Drawn in the format of the video, so high bit depth video isn't
+        // quantised by a conversion to RGB and back
+        let levels = state.levels;
+        let data = map.as_mut_slice();
+        match state.info.format() {
+            gst_video::VideoFormat::I420 => draw_yuv(data, &state.info, &image, offsets, &levels, 1, 0, false),
+            gst_video::VideoFormat::I42010le => draw_yuv(data, &state.info, &image, offsets, &levels, 2, 0, false),
+            gst_video::VideoFormat::P01010le => draw_yuv(data, &state.info, &image, offsets, &levels, 2, 6, true),
+            _ => {
+                let stride = state.info.stride()[0] as usize;
+                let dimensions = image.dimensions();
+                for y in 0..dimensions.1 {
+                    for x in 0..dimensions.0 {
+                        let baseindex = (offsets.1 + y) as usize * stride + 4 * (offsets.0 + x) as usize;
+                        let value = (if image.get_pixel(x, y)[0] > 127 { levels.white } else { levels.black }) as u8;
+                        data[baseindex] = value;
+                        data[baseindex + 1] = value;
+                        data[baseindex + 2] = value;
+                    }
+                }
             }
         }
 
@@ -230,9 +410,11 @@ impl BaseTransformImpl<BaseTransform> for FrameId {
             Some(info) => info,
         };
 
+        let first_index = self.settings.lock().unwrap().first_index;
         *self.state.lock().unwrap() = Some(State {
+            levels: Levels::new(&info, incaps),
             info: info,
-            frame_index: 0
+            frame_index: first_index
         });
 
         true
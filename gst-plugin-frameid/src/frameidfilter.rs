@@ -133,22 +133,45 @@ impl FrameIdFilter {
         Box::new(imp)
     }
 
-    fn inspect_codes(&self, image : GrayImage) -> (gst::FlowReturn, String) {
+    fn post_decode_failure(&self, element: &BaseTransform, reason: &str) {
+        gst_warning!(self.cat, obj: element, "Failed to decode qrcode: {}", reason);
+
+        let structure = gst::Structure::new("frameid-decode-failed", &[
+            ("reason", &reason)]);
+        element.post_message(&gst::Message::new_element(structure).src(Some(element)).build());
+    }
+
+    fn inspect_codes(&self, element: &BaseTransform, image : GrayImage) -> (gst::FlowReturn, String) {
         let settings = self.settings.lock().unwrap();
 
-        let mut quirc = QrCoder::new().unwrap();
+        let mut quirc = match QrCoder::new() {
+            Ok(quirc) => quirc,
+            Err(err) => {
+                gst_element_error!(element, gst::LibraryError::Init, ["Failed to create qrcode decoder: {:?}", err]);
+                return (gst::FlowReturn::Error, "".to_owned());
+            }
+        };
         let width  = image.width();
         let height = image.height();
-        let codes  = quirc.codes(&image, width, height).unwrap();
+        let codes  = match quirc.codes(&image, width, height) {
+            Ok(codes) => codes,
+            Err(err) => {
+                gst_element_error!(element, gst::StreamError::Decode, ["Failed to scan frame for qrcodes: {:?}", err]);
+                return (gst::FlowReturn::Error, "".to_owned());
+            }
+        };
 
         for code in codes {
             match code {
                 Ok(code) => {
                     let s = match str::from_utf8(&code.payload) {
                         Ok(v) => v,
-                        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+                        Err(e) => {
+                            self.post_decode_failure(element, &format!("Invalid UTF-8 sequence: {}", e));
+                            continue;
+                        }
                     };
-                    println!("Code: {:?}", s);
+                    gst_debug!(self.cat, obj: element, "Found qrcode {:?}", s);
                     match settings.prefix {
                         Some(ref p) => {
                             if s.starts_with(p) {
@@ -158,7 +181,7 @@ impl FrameIdFilter {
                         None => return (gst::FlowReturn::Ok, s.to_owned())
                     }
                 }
-                Err(err) => println!("{:?}", err),
+                Err(err) => self.post_decode_failure(element, &format!("{:?}", err)),
             }
         }
 
@@ -183,7 +206,7 @@ impl ObjectImpl<BaseTransform> for FrameIdFilter {
             }
             Property::UInt("qrcode-size", ..) => {
                 let mut settings = self.settings.lock().unwrap();
-                settings.qrcode_size = value.get().unwrap();
+                settings.qrcode_size = value.get().unwrap_or(0);
             }
             _ => unimplemented!(),
         }
@@ -201,7 +224,7 @@ impl ObjectImpl<BaseTransform> for FrameIdFilter {
                 let settings = self.settings.lock().unwrap();
                 Ok(settings.position.to_value())
             }
-            Property::String("qrcode-size", ..) => {
+            Property::UInt("qrcode-size", ..) => {
                 let settings = self.settings.lock().unwrap();
                 Ok(settings.qrcode_size.to_value())
             }
@@ -216,12 +239,18 @@ impl BaseTransformImpl<BaseTransform> for FrameIdFilter {
     fn transform_ip(&self, element: &BaseTransform, buf: &mut gst::BufferRef) -> gst::FlowReturn {
         let mut state_guard = self.state.lock().unwrap();
         let state = match *state_guard {
-            None => return gst::FlowReturn::NotNegotiated,
+            None => {
+                gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                return gst::FlowReturn::NotNegotiated;
+            }
             Some(ref mut state) => state,
         };
 
         let map = match buf.map_readable() {
-            None => return gst::FlowReturn::Error,
+            None => {
+                gst_element_error!(element, gst::CoreError::Failed, ["Failed to map buffer readable"]);
+                return gst::FlowReturn::Error;
+            }
             Some(map) => map,
         };
 
@@ -231,6 +260,16 @@ impl BaseTransformImpl<BaseTransform> for FrameIdFilter {
             x => DynamicImage::new_luma8(x, x).to_luma()
         };
 
+        if image.width() > state.info.width() || image.height() > state.info.height() {
+            gst_element_error!(
+                element,
+                gst::StreamError::Format,
+                ["qrcode-size {} is larger than the {}x{} frame",
+                 settings.qrcode_size, state.info.width(), state.info.height()]
+            );
+            return gst::FlowReturn::Error;
+        }
+
         let offsets = match settings.position.as_ref().map(String::as_ref) {
             Some("top-right") => (state.info.width() - image.width(), 0),
             Some("bottom-left") => (0, state.info.height() - image.height()),
@@ -238,8 +277,6 @@ impl BaseTransformImpl<BaseTransform> for FrameIdFilter {
             _ => (0, 0),
         };
 
-        println!("Frame: {:?} {:?}", image.dimensions(), offsets);
-
         let dimensions = image.dimensions();
         for y in 0..dimensions.1 {
             for x in 0..dimensions.0 {
@@ -248,9 +285,11 @@ impl BaseTransformImpl<BaseTransform> for FrameIdFilter {
                 pixel[0] = map.as_slice()[baseindex]/3 + map.as_slice()[baseindex+1]/3 + map.as_slice()[baseindex+2]/3;
             }
         }
-        println!("Inspecting codes");
-        let ret = self.inspect_codes(image);
-        println!("Finished codes");
+        // inspect_codes() takes the settings lock again
+        drop(settings);
+
+        gst_debug!(self.cat, obj: element, "Scanning the {:?} region at {:?} for qrcodes", dimensions, offsets);
+        let ret = self.inspect_codes(element, image);
 
         match ret.0 {
             gst::FlowReturn::Ok => {
@@ -0,0 +1,329 @@
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches};
+use frameid_common::uri::to_uri;
+use glib;
+
+use metric::Registry;
+
+pub const FORMATS: [&str; 2] = ["y4m", "raw"];
+
+/// File format the aligned frames are written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// YUV4MPEG2, with the size, framerate, interlacing and colorspace in its
+    /// header
+    Y4m,
+    /// Headerless I420 frames
+    Raw,
+}
+
+impl OutputFormat {
+    fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "y4m" => Some(OutputFormat::Y4m),
+            "raw" => Some(OutputFormat::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Y4m => "y4m",
+            OutputFormat::Raw => "I420",
+        }
+    }
+}
+
+pub const METRICS_FORMATS: [&str; 3] = ["json", "csv", "none"];
+
+/// Format of the per-frame quality metrics, if they're computed at all
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricsFormat {
+    /// Per-frame scores and the summary
+    Json,
+    /// Per-frame scores only
+    Csv,
+    None,
+}
+
+impl MetricsFormat {
+    fn from_name(name: &str) -> Option<MetricsFormat> {
+        match name {
+            "json" => Some(MetricsFormat::Json),
+            "csv" => Some(MetricsFormat::Csv),
+            "none" => Some(MetricsFormat::None),
+            _ => None,
+        }
+    }
+}
+
+/// Pixels removed from each side of both videos before they're written
+#[derive(Debug, Clone)]
+pub struct Cropping {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Cropping {
+    /// Parses LEFT[,TOP[,RIGHT[,BOTTOM]]], missing sides aren't cropped
+    fn parse(value: &str) -> Result<Cropping, String> {
+        let invalid = || format!("'{}' is not a cropping like LEFT,TOP,RIGHT,BOTTOM in pixels", value);
+        let sides = value.split(',')
+            .map(|side| side.trim().parse::<i32>().ok().filter(|&side| side >= 0).ok_or_else(invalid))
+            .collect::<Result<Vec<i32>, String>>()?;
+        if sides.len() > 4 {
+            return Err(invalid());
+        }
+
+        let side = |index: usize| sides.get(index).cloned().unwrap_or(0);
+        Ok(Cropping { left: side(0), top: side(1), right: side(2), bottom: side(3) })
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub reference: String,
+    pub capture: String,
+    pub cropping: Cropping,
+    /// Where the aligned frames of each input are written
+    pub reference_output: String,
+    pub capture_output: String,
+    pub format: OutputFormat,
+    pub metrics_format: MetricsFormat,
+    /// Names of the metrics computed on every aligned pair
+    pub metrics: Vec<String>,
+    /// Write the capture frames in the reference's order instead of the
+    /// order they arrived in
+    pub reorder: bool,
+    /// Id prefix of the frames that are aligned
+    pub prefix: String,
+    /// Id prefixes of the pre-roll and post-roll frames
+    pub start_prefix: String,
+    pub end_prefix: String,
+}
+
+/// Output path for the aligned frames of `uri`. `name` may use {name}, the
+/// input's file name, and {role}, reference or capture.
+fn output_path(uri: &str, role: &str, directory: Option<&str>, name: &str, format: OutputFormat) -> String {
+    let local = glib::filename_from_uri(uri).ok().map(|(path, _)| path);
+    let file_name = match local {
+        Some(ref path) => path.file_name().map(|name| name.to_string_lossy().into_owned()),
+        None => uri.split(|c| c == '?' || c == '#').next().unwrap()
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned),
+    }.unwrap_or_else(|| role.to_owned());
+
+    // Next to the input if it's a local file, otherwise in the current directory
+    let directory = match (directory, local.as_ref().and_then(|path| path.parent())) {
+        (Some(directory), _) => Path::new(directory).to_path_buf(),
+        (None, Some(parent)) => parent.to_path_buf(),
+        (None, None) => Path::new(".").to_path_buf(),
+    };
+
+    let name = name.replace("{name}", &file_name).replace("{role}", role);
+    directory.join(format!("{}.{}", name, format.extension())).to_string_lossy().into_owned()
+}
+
+fn validate_input(value: String) -> Result<(), String> {
+    if value.contains("://") || Path::new(&value).exists() {
+        Ok(())
+    } else {
+        Err(format!("{} does not exist", value))
+    }
+}
+
+fn validate_crop(value: String) -> Result<(), String> {
+    Cropping::parse(&value).map(|_| ())
+}
+
+/// `metrics` are the names and descriptions of the registered metrics
+fn validate_metric(metrics: &[(&'static str, &'static str)], value: String) -> Result<(), String> {
+    if metrics.iter().any(|&(name, _)| name == value) {
+        return Ok(());
+    }
+
+    let available: Vec<String> = metrics.iter()
+        .map(|&(name, description)| format!("  {}: {}", name, description))
+        .collect();
+    Err(format!("unknown metric '{}', available metrics are:\n{}", value, available.join("\n")))
+}
+
+fn validate_output_dir(value: String) -> Result<(), String> {
+    if Path::new(&value).is_dir() {
+        Ok(())
+    } else {
+        Err(format!("{} is not a directory", value))
+    }
+}
+
+fn app<'a, 'b>(registry: &Registry) -> App<'a, 'b> {
+    let metrics = registry.list();
+    App::new("video-align")
+        .about("Aligns a capture of a video prepared with video-frameid-prepare with its reference, frame by frame")
+        .arg(Arg::with_name("reference")
+             .long("reference")
+             .short("r")
+             .value_name("INPUT")
+             .required(true)
+             .validator(validate_input)
+             .help("Prepared reference video, as a file path or URI"))
+        .arg(Arg::with_name("capture")
+             .long("capture")
+             .short("c")
+             .value_name("INPUT")
+             .required(true)
+             .validator(validate_input)
+             .help("Capture of the reference, as a file path or URI"))
+        .arg(Arg::with_name("crop")
+             .long("crop")
+             .value_name("LEFT,TOP,RIGHT,BOTTOM")
+             .default_value("0,0,0,0")
+             .validator(validate_crop)
+             .help("Pixels cropped from each side of both videos, e.g. to remove the borders of a capture"))
+        .arg(Arg::with_name("output-dir")
+             .long("output-dir")
+             .short("o")
+             .value_name("DIR")
+             .validator(validate_output_dir)
+             .help("Directory the aligned frames are written to, by default next to each input (or the current directory for remote inputs)"))
+        .arg(Arg::with_name("output-name")
+             .long("output-name")
+             .value_name("TEMPLATE")
+             .default_value("{name}")
+             .help("Name of the aligned outputs, without extension: {name} is the input's file name, {role} reference or capture"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .value_name("FORMAT")
+             .possible_values(&FORMATS)
+             .default_value("y4m")
+             .help("Format of the aligned outputs: y4m, or raw I420 frames whose size and framerate have to be passed on separately"))
+        .arg(Arg::with_name("metrics-format")
+             .long("metrics-format")
+             .value_name("FORMAT")
+             .possible_values(&METRICS_FORMATS)
+             .default_value("json")
+             .help("Format the per-frame PSNR, SSIM and MS-SSIM of the aligned pairs are written in, next to the capture's output, or none to skip them"))
+        .arg(Arg::with_name("metric")
+             .long("metric")
+             .value_name("NAME")
+             .multiple(true)
+             .number_of_values(1)
+             .use_delimiter(true)
+             .default_value("psnr,ssim,ms-ssim")
+             .validator(move |value| validate_metric(&metrics, value))
+             .help("Metric computed on the aligned pairs, comma separated or repeated (built in: psnr, ssim, ms-ssim)"))
+        .arg(Arg::with_name("reorder")
+             .long("reorder")
+             .value_name("ORDER")
+             .possible_values(&["id", "none"])
+             .default_value("id")
+             .help("Order the capture frames are written in: id puts them in the reference's order (dropping frames without a reference id), none keeps the order they arrived in"))
+        .arg(Arg::with_name("prefix")
+             .long("prefix")
+             .value_name("PREFIX")
+             .default_value("f:")
+             .help("Id prefix of the frames that are aligned"))
+        .arg(Arg::with_name("start-prefix")
+             .long("start-prefix")
+             .value_name("PREFIX")
+             .default_value("s:")
+             .help("Id prefix of the pre-roll frames"))
+        .arg(Arg::with_name("end-prefix")
+             .long("end-prefix")
+             .value_name("PREFIX")
+             .default_value("e:")
+             .help("Id prefix of the post-roll frames"))
+}
+
+impl Config {
+    /// `registry` has the metrics --metric can enable
+    pub fn from_args(registry: &Registry) -> Result<Config, String> {
+        Config::from_matches(&app(registry).get_matches())
+    }
+
+    /// Where the per-frame metrics are written
+    pub fn metrics_path(&self) -> String {
+        let extension = if self.metrics_format == MetricsFormat::Csv { "csv" } else { "json" };
+        Path::new(&self.capture_output)
+            .with_extension(format!("metrics.{}", extension))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Where the alignment report is written
+    pub fn report_path(&self) -> String {
+        Path::new(&self.capture_output)
+            .with_extension("report.json")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Config, String> {
+        // Validators already ran, so only conversion errors are left
+        let value = |name| matches.value_of(name).unwrap().to_owned();
+
+        let reference = to_uri(&value("reference"))?;
+        let capture = to_uri(&value("capture"))?;
+        let output_dir = matches.value_of("output-dir");
+        let output_name = value("output-name");
+        let format = OutputFormat::from_name(&value("format")).unwrap();
+        let reference_output = output_path(&reference, "reference", output_dir, &output_name, format);
+        let capture_output = output_path(&capture, "capture", output_dir, &output_name, format);
+        if reference_output == capture_output {
+            return Err(format!("Reference and capture would both be written to {}, use {{role}} in --output-name",
+                               reference_output));
+        }
+
+        Ok(Config {
+            reference,
+            capture,
+            cropping: Cropping::parse(&value("crop"))?,
+            reference_output,
+            capture_output,
+            format,
+            metrics_format: MetricsFormat::from_name(&value("metrics-format")).unwrap(),
+            metrics: matches.values_of("metric").unwrap().map(str::to_owned).collect(),
+            reorder: matches.value_of("reorder") == Some("id"),
+            prefix: value("prefix"),
+            start_prefix: value("start-prefix"),
+            end_prefix: value("end-prefix"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sides(value: &str) -> Result<(i32, i32, i32, i32), String> {
+        Cropping::parse(value).map(|c| (c.left, c.top, c.right, c.bottom))
+    }
+
+    #[test]
+    fn missing_sides_are_not_cropped() {
+        assert_eq!(sides("8"), Ok((8, 0, 0, 0)));
+        assert_eq!(sides("1, 2,3"), Ok((1, 2, 3, 0)));
+    }
+
+    #[test]
+    fn cropping_has_at_most_four_non_negative_sides() {
+        assert_eq!(sides("1,2,3,4"), Ok((1, 2, 3, 4)));
+        assert_eq!(sides("1,2,3,4,5"), Err("'1,2,3,4,5' is not a cropping like LEFT,TOP,RIGHT,BOTTOM in pixels".to_owned()));
+        assert!(sides("0,-1").is_err());
+    }
+
+    #[test]
+    fn outputs_go_next_to_local_inputs() {
+        assert_eq!(output_path("file:///videos/capture.mp4", "capture", None, "{name}", OutputFormat::Y4m),
+                   "/videos/capture.mp4.y4m");
+        assert_eq!(output_path("http://example.com/live/stream.ts?token=1", "capture", Some("/out"), "{name}-{role}", OutputFormat::Raw),
+                   "/out/stream.ts-capture.I420");
+        // Nothing to name it after
+        assert_eq!(output_path("http://example.com/", "reference", None, "{name}", OutputFormat::Y4m), "./reference.y4m");
+    }
+}
@@ -0,0 +1,221 @@
+use failure::Error;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use config::OutputFormat;
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} is not a 4:2:0 Y4M file: {}", path, reason)]
+struct InvalidY4m {
+    path: String,
+    reason: String,
+}
+
+/// One aligned I420 frame
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// Y, U and V
+    pub planes: [Vec<u8>; 3],
+    /// Bytes per line of each plane
+    pub strides: [usize; 3],
+}
+
+impl Frame {
+    /// Width and height of `plane`
+    pub fn plane_size(&self, plane: usize) -> (usize, usize) {
+        if plane == 0 {
+            (self.width, self.height)
+        } else {
+            ((self.width + 1) / 2, (self.height + 1) / 2)
+        }
+    }
+}
+
+/// Where the planes of an I420 frame are in its bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    /// Of the Y, U and V planes, from the start of the frame
+    pub offsets: [usize; 3],
+    /// Bytes per line of each plane
+    pub strides: [usize; 3],
+    /// Bytes per frame, padding included
+    pub size: usize,
+}
+
+impl Layout {
+    /// Planes one after the other without padding, as Y4M stores them
+    pub fn packed(width: usize, height: usize) -> Layout {
+        let chroma_width = (width + 1) / 2;
+        let chroma_size = chroma_width * ((height + 1) / 2);
+        Layout {
+            width,
+            height,
+            offsets: [0, width * height, width * height + chroma_size],
+            strides: [width, chroma_width, chroma_width],
+            size: width * height + 2 * chroma_size,
+        }
+    }
+
+    /// Splits the bytes of a frame into its planes
+    fn frame(&self, data: &[u8]) -> Frame {
+        let plane = |n: usize| {
+            let rows = if n == 0 { self.height } else { (self.height + 1) / 2 };
+            let end = (self.offsets[n] + self.strides[n] * rows).min(data.len());
+            data[self.offsets[n]..end].to_vec()
+        };
+        Frame {
+            width: self.width,
+            height: self.height,
+            planes: [plane(0), plane(1), plane(2)],
+            strides: self.strides,
+        }
+    }
+}
+
+/// Reads the frames of an aligned output back
+pub struct FrameReader {
+    reader: BufReader<File>,
+    format: OutputFormat,
+    layout: Layout,
+    /// Bytes before the first frame
+    header_length: usize,
+    /// Offset and length of every frame, once one was read by position
+    index: Option<Vec<(u64, u64)>>,
+}
+
+impl FrameReader {
+    /// Opens the output at `path`. Raw outputs have no header, so they need
+    /// the `layout` they were written with.
+    pub fn open(path: &str, format: OutputFormat, layout: Option<Layout>) -> Result<FrameReader, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let invalid = |reason: &str| InvalidY4m { path: path.to_owned(), reason: reason.to_owned() };
+
+        let mut header = String::new();
+        let layout = match format {
+            OutputFormat::Y4m => {
+                reader.read_line(&mut header)?;
+                let mut words = header.trim_right().split(' ');
+                if words.next() != Some("YUV4MPEG2") {
+                    Err(invalid("no YUV4MPEG2 signature"))?;
+                }
+
+                let (mut width, mut height) = (None, None);
+                for word in words.filter(|word| !word.is_empty()) {
+                    let (tag, value) = word.split_at(1);
+                    match tag {
+                        "W" => width = value.parse::<usize>().ok(),
+                        "H" => height = value.parse::<usize>().ok(),
+                        // No colorspace means 4:2:0, and every 4:2:0 chroma
+                        // siting has the same layout
+                        "C" if !value.starts_with("420") => Err(invalid(&format!("colorspace {}", value)))?,
+                        _ => (),
+                    }
+                }
+                Layout::packed(width.ok_or_else(|| invalid("no width"))?, height.ok_or_else(|| invalid("no height"))?)
+            }
+            OutputFormat::Raw => layout.ok_or_else(|| format_err!("Layout of {} is unknown, no frame was written", path))?,
+        };
+
+        Ok(FrameReader { reader, format, layout, header_length: header.len(), index: None })
+    }
+
+    /// Offset and length of every complete frame, Y4M frame header included
+    fn index(&mut self) -> Result<Vec<(u64, u64)>, Error> {
+        let mut frames = Vec::new();
+        let mut offset = self.header_length as u64;
+        let frame_length = self.layout.size as u64;
+        self.reader.seek(SeekFrom::Start(offset))?;
+
+        loop {
+            let mut length = 0;
+            if self.format == OutputFormat::Y4m {
+                let mut line = String::new();
+                length = self.reader.read_line(&mut line)? as u64;
+                if length == 0 {
+                    break;
+                }
+            } else if self.reader.fill_buf()?.is_empty() {
+                break;
+            }
+
+            // An interrupted run can leave a partial frame at the end
+            if io::copy(&mut (&mut self.reader).take(frame_length), &mut io::sink())? < frame_length {
+                break;
+            }
+            length += frame_length;
+            frames.push((offset, length));
+            offset += length;
+        }
+
+        Ok(frames)
+    }
+
+    /// The next frame, or None at the end of the file
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.format == OutputFormat::Y4m {
+            // FRAME, possibly with parameters
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.starts_with("FRAME") {
+                Err(format_err!("Expected a Y4M FRAME header, got {:?}", line.trim_right()))?;
+            }
+        } else if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; self.layout.size];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(self.layout.frame(&data)))
+    }
+
+    /// The frame at position `n` in the file, or None if there are fewer
+    pub fn frame_at(&mut self, n: usize) -> Result<Option<Frame>, Error> {
+        if self.index.is_none() {
+            self.index = Some(self.index()?);
+        }
+        let offset = match self.index.as_ref().and_then(|index| index.get(n)) {
+            Some(&(offset, _)) => offset,
+            None => return Ok(None),
+        };
+
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.next_frame()
+    }
+}
+
+/// Rewrites the output at `path` with its frames in `order`, given as their
+/// positions in the file. Frames left out of `order` are dropped.
+pub fn reorder(path: &str, format: OutputFormat, layout: Option<Layout>, order: &[usize]) -> Result<(), Error> {
+    let (header_length, index) = {
+        let mut reader = FrameReader::open(path, format, layout)?;
+        (reader.header_length, reader.index()?)
+    };
+
+    let temporary = format!("{}.reordering", path);
+    {
+        let mut input = File::open(path)?;
+        let mut output = BufWriter::new(File::create(&temporary)?);
+        let mut buffer = vec![0; header_length];
+        input.read_exact(&mut buffer)?;
+        output.write_all(&buffer)?;
+
+        for &(offset, length) in order.iter().filter_map(|&n| index.get(n)) {
+            buffer.resize(length as usize, 0);
+            input.seek(SeekFrom::Start(offset))?;
+            input.read_exact(&mut buffer)?;
+            output.write_all(&buffer)?;
+        }
+        output.flush()?;
+    }
+
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,391 @@
+//! Aligns a capture of a video prepared with video-frameid-prepare with its
+//! reference, frame by frame, and scores the aligned pairs.
+//!
+//! The video-align binary runs the built-in metrics. To score with others,
+//! implement `Metric`, register it and run with that registry:
+//!
+//! ```no_run
+//! extern crate video_align;
+//!
+//! use video_align::{AlignedPair, Metric, Registry};
+//!
+//! struct Brightness;
+//!
+//! impl Metric for Brightness {
+//!     fn measure(&mut self, pair: &AlignedPair) -> Vec<(String, f64)> {
+//!         let mean = |plane: &[u8]| plane.iter().map(|&v| v as f64).sum::<f64>() / plane.len() as f64;
+//!         vec![("brightness_delta".to_owned(), mean(&pair.capture.planes[0]) - mean(&pair.reference.planes[0]))]
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut registry = Registry::new();
+//!     registry.register("brightness", "Mean luma difference", || Box::new(Brightness));
+//!     video_align::run(registry);
+//! }
+//! ```
+
+#[macro_use]
+extern crate gstreamer as gst;
+use gst::prelude::*;
+extern crate gstreamer_video as gst_video;
+extern crate gstreamer_app as gst_app;
+extern crate gstreamer_sys as gst_ffi;
+extern crate glib_sys as glib_ffi;
+extern crate libc;
+extern crate clap;
+extern crate frameid_common;
+
+extern crate glib;
+use glib::translate::*;
+use glib::signal::SignalHandlerId;
+macro_rules! callback_guard {
+    () => (
+                let _guard = ::glib::CallbackGuard::new();
+                    )
+}
+
+#[macro_use]
+extern crate failure;
+use failure::Error;
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::process;
+use std::error::Error as StdError;
+use std::boxed::Box as Box_;
+use std::mem::{self, transmute};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+#[macro_use]
+extern crate failure_derive;
+
+mod config;
+mod frames;
+mod metric;
+mod metrics;
+mod quality;
+mod reorder;
+mod report;
+
+use config::{Config, Cropping, MetricsFormat, OutputFormat};
+use frameid_common::interrupt;
+use frames::Layout;
+use report::Report;
+
+pub use frames::Frame;
+pub use metric::{AlignedPair, Metric, Registry};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Missing element {}", _0)]
+struct MissingElement(&'static str);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Received error from {}: {} (debug: {:?})", src, error, debug)]
+struct ErrorMessage {
+    src: String,
+    error: String,
+    debug: Option<String>,
+    #[cause] cause: glib::Error,
+}
+
+/// What one pipeline wrote to its output
+#[derive(Debug, Default)]
+struct Written {
+    /// Ids of the frames, in output order. Frames without one are still
+    /// written.
+    ids: Vec<Option<String>>,
+    /// Where the planes of the frames are
+    layout: Option<Layout>,
+    /// Codes read from every frame of the input, written or not, in order
+    codes: Vec<Vec<String>>,
+}
+
+unsafe extern "C" fn code_detected_trampoline(this: *mut gst_ffi::GstElement, object: *mut libc::c_char, f: glib_ffi::gpointer) -> bool {
+    callback_guard!();
+    let f: &&(Fn(&gst::Element, &str) -> bool + Send + 'static) = transmute(f);
+        f(&from_glib_borrow(this), &String::from_glib_none(object))
+}
+
+fn connect_to_code_detected<F: Fn(&gst::Element, &str) -> bool + Send + 'static>(el : &gst::Element, f: F) -> SignalHandlerId {
+    unsafe {
+        let f: Box_<Box_<Fn(&gst::Element, &str) -> bool + Send + 'static>> = Box_::new(Box_::new(f));
+            glib::signal::connect(el.to_glib_none().0, "code-detected",
+                transmute(code_detected_trampoline as usize), Box_::into_raw(f) as *mut _)
+    }
+}
+
+/// zbar reports the codes of a frame while it's processing it, so they
+/// belong to the last frame that reached it
+fn record_code(written : &Mutex<Written>, code : &str) {
+    if let Some(codes) = written.lock().unwrap().codes.last_mut() {
+        codes.push(code.to_owned());
+    }
+}
+
+fn setup_pipeline(path : &String, output : &str, format : OutputFormat, prefix : &str, cropping : &Cropping, codes : Arc<Mutex<HashSet<String>>>, written : Arc<Mutex<Written>>, filter_by_codes : bool) -> gst::Pipeline {
+    let pipeline = gst::Pipeline::new(None);
+    let uridec = gst::ElementFactory::make("uridecodebin", None).ok_or(MissingElement("uridecodebin")).unwrap();
+
+    uridec.set_property("uri", &glib::Value::from(path)).unwrap();
+    pipeline.add(&uridec).unwrap();
+
+    let output = output.to_owned();
+    let prefix = prefix.to_owned();
+    let pipeline_clone = pipeline.clone();
+    let cropping_clone = cropping.clone();
+    uridec.connect_pad_added(move |_, src_pad| {
+        // FIXME post an error message if any of those fail instead of just doing unwrap()
+        if !src_pad.get_current_caps().unwrap().get_structure(0).unwrap().get_name().contains("video") {
+            return;
+        }
+        let queue = gst::ElementFactory::make("queue", None).unwrap();
+        let videoconvert = gst::ElementFactory::make("videoconvert", None).unwrap();
+        let zbar = gst::ElementFactory::make("zbar", None).unwrap();
+        let videoconvert2 = gst::ElementFactory::make("videoconvert", None).unwrap();
+        let videocrop = gst::ElementFactory::make("videocrop", None).unwrap();
+        let capsfilter = gst::ElementFactory::make("capsfilter", None).unwrap();
+        let filesink = gst::ElementFactory::make("filesink", None).unwrap();
+        let codesclone = codes.clone();
+        let writtenclone = written.clone();
+        let prefix = prefix.clone();
+        let frameswritten = written.clone();
+        zbar.get_static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+            frameswritten.lock().unwrap().codes.push(Vec::new());
+            gst::PadProbeReturn::Ok
+        });
+        // Frames zbar lets through are written, with the id it just read
+        let idswritten = written.clone();
+        let idprefix = prefix.clone();
+        zbar.get_static_pad("src").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+            let mut written = idswritten.lock().unwrap();
+            let id = written.codes.last()
+                .and_then(|codes| codes.iter().find(|code| code.starts_with(&idprefix)).cloned());
+            written.ids.push(id);
+            gst::PadProbeReturn::Ok
+        });
+
+        if !filter_by_codes {
+            connect_to_code_detected(&zbar, move |_el, code| {
+                println!("Code: {:?}", code);
+                record_code(&writtenclone, code);
+                let mut ret = code.starts_with(&prefix);
+                if ret {
+                    let mut codesdata = codesclone.lock().unwrap();
+                    let codestr = code.to_owned();
+                    if !codesdata.contains(&codestr) {
+                        codesdata.insert(codestr);
+                    } else {
+                        ret = false; // repeated frame
+                    }
+                }
+                !ret
+            });
+        } else {
+            connect_to_code_detected(&zbar, move |_el, code| {
+                record_code(&writtenclone, code);
+                let mut ret = code.starts_with(&prefix);
+                if ret {
+                    let mut codesdata = codesclone.lock().unwrap();
+                    let codestr = code.to_owned();
+                    if codesdata.contains(&codestr) {
+                       codesdata.remove(&codestr);
+                    } else {
+                        ret = false; // frame is not present in our codes list
+                    }
+                }
+                println!("Code: {:?} {:?}", code, ret);
+                !ret
+            });
+        }
+
+        let y4menc = match format {
+            OutputFormat::Y4m => Some(gst::ElementFactory::make("y4menc", None).unwrap()),
+            OutputFormat::Raw => None,
+        };
+
+        filesink.set_property("location", &output).unwrap();
+        capsfilter.set_property("caps", &gst::Caps::from_string("video/x-raw, format=(string)I420")).unwrap();
+        videocrop.set_property("top", &cropping_clone.top).unwrap();
+        videocrop.set_property("left", &cropping_clone.left).unwrap();
+        videocrop.set_property("right", &cropping_clone.right).unwrap();
+        videocrop.set_property("bottom", &cropping_clone.bottom).unwrap();
+
+        let pipeline = &pipeline_clone;
+        pipeline.add_many(&[&queue, &videoconvert, &zbar, &videoconvert2, &videocrop,
+                          &capsfilter, &filesink]).unwrap();;
+        gst::Element::link_many(&[&queue, &videoconvert, &zbar, &videoconvert2,
+                                &videocrop, &capsfilter]).unwrap();
+        match y4menc {
+            Some(ref y4menc) => {
+                pipeline.add(y4menc).unwrap();
+                gst::Element::link_many(&[&capsfilter, y4menc, &filesink]).unwrap();
+            }
+            None => capsfilter.link(&filesink).unwrap(),
+        }
+
+        filesink.sync_state_with_parent().unwrap();
+        if let Some(ref y4menc) = y4menc {
+            y4menc.sync_state_with_parent().unwrap();
+        }
+        capsfilter.sync_state_with_parent().unwrap();
+        videocrop.sync_state_with_parent().unwrap();
+        videoconvert2.sync_state_with_parent().unwrap();
+        zbar.sync_state_with_parent().unwrap();
+        videoconvert.sync_state_with_parent().unwrap();
+        queue.sync_state_with_parent().unwrap();
+
+        // Raw outputs don't say how big their frames are, nor how GStreamer
+        // padded their lines
+        let writtenclone = written.clone();
+        capsfilter.get_static_pad("src").unwrap().add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |pad, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if let gst::EventView::Caps(e) = event.view() {
+                    if let Some(info) = gst_video::VideoInfo::from_caps(e.get_caps()) {
+                        let (width, height) = (info.width() as usize, info.height() as usize);
+                        if width % 2 != 0 || height % 2 != 0 {
+                            if let Some(element) = pad.get_parent_element() {
+                                gst_element_error!(element, gst::StreamError::Format,
+                                                   ["Cropped frames are {}x{}, I420 needs an even width and height", width, height]);
+                            }
+                            return gst::PadProbeReturn::Drop;
+                        }
+
+                        let (offset, stride) = (info.offset(), info.stride());
+                        writtenclone.lock().unwrap().layout = Some(Layout {
+                            width,
+                            height,
+                            offsets: [offset[0], offset[1], offset[2]],
+                            strides: [stride[0] as usize, stride[1] as usize, stride[2] as usize],
+                            size: info.size(),
+                        });
+                    }
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        let queue_sink_pad = queue.get_static_pad("sink").unwrap();
+        assert_eq!(src_pad.link(&queue_sink_pad), gst::PadLinkReturn::Ok);
+    });
+
+
+    pipeline
+}
+
+/// Runs `pipeline` to the end. SIGINT and SIGTERM send it EOS so the
+/// output still gets everything written so far.
+fn run_pipeline(pipeline : &gst::Pipeline) -> Result<(), Error> {
+    pipeline.set_state(gst::State::Playing).into_result()?;
+
+    let bus = pipeline
+        .get_bus()
+        .expect("Pipeline without bus. Shouldn't happen!");
+
+    let mut eos_sent = false;
+    loop {
+        use gst::MessageView;
+
+        if interrupt::is_interrupted() && !eos_sent {
+            pipeline.send_event(gst::Event::new_eos().build());
+            eos_sent = true;
+        }
+
+        let msg = match bus.timed_pop(gst::ClockTime::from_mseconds(100)) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        match msg.view() {
+            MessageView::Eos(..) => break,
+            MessageView::Error(err) => {
+                pipeline.set_state(gst::State::Null).into_result()?;
+                Err(ErrorMessage {
+                    src: msg.get_src()
+                        .map(|s| s.get_path_string())
+                        .unwrap_or_else(|| String::from("None")),
+                    error: err.get_error().description().into(),
+                    debug: err.get_debug(),
+                    cause: err.get_error(),
+                })?;
+            }
+            _ => (),
+        }
+    }
+
+    pipeline.set_state(gst::State::Null).into_result()?;
+    Ok(())
+}
+
+fn analyze_capture(config : &Config, codes : &Arc<Mutex<HashSet<String>>>) -> Result<Written, Error> {
+    let written = Arc::new(Mutex::new(Written::default()));
+    let pipeline = setup_pipeline(&config.capture, &config.capture_output, config.format, &config.prefix, &config.cropping, codes.clone(), written.clone(), false);
+    run_pipeline(&pipeline)?;
+
+    let mut written = written.lock().unwrap();
+    Ok(mem::replace(&mut *written, Written::default()))
+}
+
+fn extract_reference(config : &Config, codes : &Arc<Mutex<HashSet<String>>>) -> Result<Written, Error> {
+    let written = Arc::new(Mutex::new(Written::default()));
+    let pipeline = setup_pipeline(&config.reference, &config.reference_output, config.format, &config.prefix, &config.cropping, codes.clone(), written.clone(), true);
+    run_pipeline(&pipeline)?;
+
+    let mut written = written.lock().unwrap();
+    Ok(mem::replace(&mut *written, Written::default()))
+}
+
+/// Aligns the capture with the reference, then reports and scores them
+fn align(config : &Config, registry : &Registry) -> Result<(), Error> {
+    let codes = Arc::new(Mutex::new(HashSet::<String>::new()));
+    let mut capture = analyze_capture(config, &codes)?;
+    // The reference can't be matched against a partially analyzed capture
+    if interrupt::is_interrupted() {
+        process::exit(130);
+    }
+    let reference = extract_reference(config, &codes)?;
+    if interrupt::is_interrupted() {
+        process::exit(130);
+    }
+
+    let mut report = Report::new(config, &reference.codes, &capture.codes);
+    if config.reorder {
+        let reference_ids = report::content_ids(config, &reference.codes);
+        report.set_reordered(reorder::reorder(config, &mut capture, &reference_ids)?);
+    }
+    report.print_summary();
+    let report_path = config.report_path();
+    report.write(&report_path)?;
+    println!("Wrote alignment report to {}", report_path);
+
+    if config.metrics_format != MetricsFormat::None {
+        let mut metrics = metrics::create(registry, &config.metrics);
+        metrics::run(config, &mut metrics, &reference, &capture)?;
+    }
+
+    Ok(())
+}
+
+/// Runs video-align on the command line arguments, with the metrics of
+/// `registry` available to --metric. Exits the process on errors.
+pub fn run(registry : Registry) {
+    let config = match Config::from_args(&registry) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error! {}", e);
+            process::exit(1);
+        }
+    };
+
+    gst::init().unwrap();
+    interrupt::install().unwrap();
+
+    if let Err(e) = align(&config, &registry) {
+        eprintln!("Error! {}", e);
+        process::exit(1);
+    }
+}
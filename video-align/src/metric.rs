@@ -0,0 +1,88 @@
+use frames::Frame;
+use quality;
+
+/// An aligned reference and capture frame
+pub struct AlignedPair<'a> {
+    /// Frame id both frames carry
+    pub id: &'a str,
+    pub reference: &'a Frame,
+    pub capture: &'a Frame,
+}
+
+/// A quality metric computed on every aligned pair. Implement it and
+/// register it to make it available with --metric.
+pub trait Metric {
+    /// Named values measured on `pair`, e.g. [("psnr_y", 42.0)]. Every pair
+    /// should get the same names, in the same order.
+    fn measure(&mut self, pair: &AlignedPair) -> Vec<(String, f64)>;
+}
+
+struct Entry {
+    name: &'static str,
+    description: &'static str,
+    create: Box<Fn() -> Box<Metric>>,
+}
+
+/// Metrics that can be enabled by name
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    /// Registry with the built-in metrics
+    pub fn new() -> Registry {
+        let mut registry = Registry { entries: Vec::new() };
+        registry.register("psnr", "PSNR of the Y, U and V planes, in dB", || Box::new(quality::Psnr));
+        registry.register("ssim", "SSIM of the luma", || Box::new(quality::Ssim));
+        registry.register("ms-ssim", "5-scale MS-SSIM of the luma", || Box::new(quality::MsSsim));
+        registry
+    }
+
+    /// Makes `name` available with --metric. `create` is called once per
+    /// run the metric is enabled in. A metric registered under the name of
+    /// another replaces it.
+    pub fn register<F: Fn() -> Box<Metric> + 'static>(&mut self, name: &'static str, description: &'static str, create: F) {
+        self.entries.retain(|entry| entry.name != name);
+        self.entries.push(Entry { name, description, create: Box::new(create) });
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<Metric>> {
+        self.entries.iter().find(|entry| entry.name == name).map(|entry| (entry.create)())
+    }
+
+    /// Names and descriptions of the registered metrics
+    pub fn list(&self) -> Vec<(&'static str, &'static str)> {
+        self.entries.iter().map(|entry| (entry.name, entry.description)).collect()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant(f64);
+
+    impl Metric for Constant {
+        fn measure(&mut self, _: &AlignedPair) -> Vec<(String, f64)> {
+            vec![("constant".to_owned(), self.0)]
+        }
+    }
+
+    #[test]
+    fn registering_a_taken_name_replaces_the_metric() {
+        let mut registry = Registry::new();
+        registry.register("constant", "Always 1", || Box::new(Constant(1.0)));
+        registry.register("psnr", "Always 2", || Box::new(Constant(2.0)));
+
+        let names: Vec<&str> = registry.list().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["ssim", "ms-ssim", "constant", "psnr"]);
+        assert!(registry.create("constant").is_some());
+        assert!(registry.create("vmaf").is_none());
+    }
+}
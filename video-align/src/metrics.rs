@@ -0,0 +1,243 @@
+use failure::Error;
+use serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::f64;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use config::{Config, MetricsFormat};
+use frames::FrameReader;
+use metric::{AlignedPair, Metric, Registry};
+use Written;
+
+/// Percentiles of every metric in the summary. Low ones matter most: a few
+/// bad frames are what viewers notice.
+const PERCENTILES: [f64; 4] = [1.0, 5.0, 25.0, 50.0];
+
+#[derive(Debug, Fail)]
+#[fail(display = "Reference is {}x{} but the capture is {}x{}, crop them to the same size", _0, _1, _2, _3)]
+struct SizeMismatch(usize, usize, usize, usize);
+
+/// Scores of one aligned pair of frames
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameScores {
+    /// Position of the reference frame in its aligned output
+    pub index: usize,
+    pub id: String,
+    pub scores: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Percentile {
+    pub percentile: f64,
+    pub value: f64,
+}
+
+/// Aggregate of one metric over all frames. Frames the metric gave no
+/// finite value for are left out, and counted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub metric: String,
+    /// NaN, written as null, when no value was finite
+    pub mean: f64,
+    pub percentiles: Vec<Percentile>,
+    pub min: f64,
+    pub non_finite: usize,
+}
+
+impl Summary {
+    fn new(metric: String, values: &[f64]) -> Summary {
+        let mut finite: Vec<f64> = values.iter().cloned().filter(|value| value.is_finite()).collect();
+        let non_finite = values.len() - finite.len();
+        if finite.is_empty() {
+            return Summary { metric, mean: f64::NAN, percentiles: Vec::new(), min: f64::NAN, non_finite };
+        }
+
+        finite.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // Nearest rank
+        let percentile = |p: f64| finite[((p / 100.0 * finite.len() as f64).ceil() as usize).max(1) - 1];
+
+        Summary {
+            metric,
+            mean: finite.iter().sum::<f64>() / finite.len() as f64,
+            percentiles: PERCENTILES.iter().map(|&p| Percentile { percentile: p, value: percentile(p) }).collect(),
+            min: finite[0],
+            non_finite,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MetricsFile<'a> {
+    reference: &'a str,
+    capture: &'a str,
+    summary: &'a [Summary],
+    frames: &'a [FrameScores],
+}
+
+/// `names` are the values of the metrics, in the order they measure them
+fn write(config: &Config, path: &str, names: &[String], frames: &[FrameScores], summary: &[Summary]) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    match config.metrics_format {
+        MetricsFormat::Csv => {
+            writeln!(writer, "index,id,{}", names.join(","))?;
+            for frame in frames {
+                let values: Vec<String> = names.iter()
+                    .map(|name| frame.scores.get(name).map(|v| v.to_string()).unwrap_or_default())
+                    .collect();
+                writeln!(writer, "{},\"{}\",{}", frame.index,
+                         frame.id.replace('"', "\"\""), values.join(","))?;
+            }
+        }
+        MetricsFormat::Json => serde_json::to_writer_pretty(&mut writer, &MetricsFile {
+            reference: &config.reference,
+            capture: &config.capture,
+            summary,
+            frames,
+        })?,
+        MetricsFormat::None => unreachable!(),
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn print_summary(frames: usize, summary: &[Summary]) {
+    println!("Scored {} aligned frames", frames);
+    for metric in summary {
+        let percentiles: Vec<String> = metric.percentiles.iter()
+            .map(|p| format!("p{} {:.4}", p.percentile, p.value))
+            .collect();
+        print!("  {:8} mean {:.4}  {}  min {:.4}", metric.metric, metric.mean, percentiles.join("  "), metric.min);
+        if metric.non_finite > 0 {
+            print!("  ({} frames without a finite value)", metric.non_finite);
+        }
+        println!();
+    }
+}
+
+/// Creates the metrics enabled by name. The names were validated against
+/// `registry` already.
+pub fn create(registry: &Registry, names: &[String]) -> Vec<Box<Metric>> {
+    names.iter().map(|name| registry.create(name).unwrap()).collect()
+}
+
+/// Scores the aligned outputs frame by frame with `metrics`, from what was
+/// written to them. Frames are paired by id: unless the capture was
+/// reordered, its output still has its frames in the order they arrived.
+pub fn run(config: &Config, metrics: &mut [Box<Metric>], reference_written: &Written, capture_written: &Written) -> Result<(), Error> {
+    let mut reference = FrameReader::open(&config.reference_output, config.format, reference_written.layout)?;
+    let mut capture = FrameReader::open(&config.capture_output, config.format, capture_written.layout)?;
+
+    // A repeated id is scored with the first frame that has it
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (position, id) in capture_written.ids.iter().enumerate() {
+        if let Some(ref id) = *id {
+            positions.entry(id.as_str()).or_insert(position);
+        }
+    }
+
+    let mut names = Vec::new();
+    let mut frames = Vec::new();
+    let mut unpaired = 0;
+    for index in 0.. {
+        let a = match reference.next_frame()? {
+            Some(a) => a,
+            None => break,
+        };
+        let id = match reference_written.ids.get(index).and_then(|id| id.clone()) {
+            Some(id) => id,
+            None => {
+                unpaired += 1;
+                continue;
+            }
+        };
+        let b = match positions.get(id.as_str()) {
+            Some(&position) => capture.frame_at(position)?,
+            None => None,
+        };
+        let b = match b {
+            Some(b) => b,
+            None => {
+                unpaired += 1;
+                continue;
+            }
+        };
+        if (a.width, a.height) != (b.width, b.height) {
+            Err(SizeMismatch(a.width, a.height, b.width, b.height))?;
+        }
+
+        let pair = AlignedPair { id: &id, reference: &a, capture: &b };
+        let mut scores = BTreeMap::new();
+        for metric in metrics.iter_mut() {
+            for (name, value) in metric.measure(&pair) {
+                if !names.contains(&name) {
+                    names.push(name.clone());
+                }
+                scores.insert(name, value);
+            }
+        }
+        frames.push(FrameScores { index, id, scores });
+    }
+    if unpaired > 0 {
+        eprintln!("{} reference frames have no capture frame with their id, they aren't scored", unpaired);
+    }
+
+    if frames.is_empty() {
+        println!("No aligned frames to score");
+        return Ok(());
+    }
+
+    let summary: Vec<Summary> = names.iter()
+        .map(|name| Summary::new(name.clone(), &frames.iter().filter_map(|frame| frame.scores.get(name).cloned()).collect::<Vec<f64>>()))
+        .collect();
+
+    let path = config.metrics_path();
+    write(config, &path, &names, &frames, &summary)?;
+    print_summary(frames.len(), &summary);
+    println!("Wrote per-frame metrics to {}", path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn percentiles(summary: &Summary) -> Vec<(f64, f64)> {
+        summary.percentiles.iter().map(|p| (p.percentile, p.value)).collect()
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        // 1 to 100, shuffled
+        let values: Vec<f64> = (0..100).map(|n| ((n * 37) % 100 + 1) as f64).collect();
+        let summary = Summary::new("psnr_y".to_owned(), &values);
+
+        assert_eq!(percentiles(&summary), vec![(1.0, 1.0), (5.0, 5.0), (25.0, 25.0), (50.0, 50.0)]);
+        assert_eq!(summary.min, 1.0);
+        assert!((summary.mean - 50.5).abs() < 1e-9);
+        assert_eq!(summary.non_finite, 0);
+    }
+
+    #[test]
+    fn percentiles_of_few_values_round_up() {
+        let summary = Summary::new("ssim".to_owned(), &[0.9, 0.5, 0.7]);
+        assert_eq!(percentiles(&summary), vec![(1.0, 0.5), (5.0, 0.5), (25.0, 0.5), (50.0, 0.7)]);
+    }
+
+    #[test]
+    fn non_finite_values_are_left_out() {
+        let summary = Summary::new("vmaf".to_owned(), &[f64::NAN, 2.0, f64::INFINITY, 1.0]);
+        assert_eq!(summary.non_finite, 2);
+        assert_eq!(summary.min, 1.0);
+        assert!((summary.mean - 1.5).abs() < 1e-9);
+
+        let summary = Summary::new("vmaf".to_owned(), &[f64::NAN]);
+        assert_eq!(summary.non_finite, 1);
+        assert!(summary.mean.is_nan());
+        assert!(summary.percentiles.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+use frames::Frame;
+use metric::{AlignedPair, Metric};
+
+/// PSNR of identical planes, which would otherwise be infinite
+const MAX_PSNR: f64 = 100.0;
+
+/// Side of the SSIM windows, which overlap by half
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Weights of the MS-SSIM scales, from the finest (Wang et al. 2003)
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// Luma samples as floats, for SSIM and its downscaled MS-SSIM versions
+struct Luma {
+    width: usize,
+    height: usize,
+    data: Vec<f64>,
+}
+
+impl Luma {
+    fn from_frame(frame: &Frame) -> Luma {
+        let data = (0..frame.height)
+            .flat_map(|y| frame.planes[0][y * frame.strides[0]..y * frame.strides[0] + frame.width].iter())
+            .map(|&sample| sample as f64)
+            .collect();
+        Luma { width: frame.width, height: frame.height, data }
+    }
+
+    /// Halved in both directions by averaging 2x2 blocks
+    fn downscale(&self) -> Luma {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let at = |dx: usize, dy: usize| self.data[(2 * y + dy) * self.width + 2 * x + dx];
+                data.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0);
+            }
+        }
+        Luma { width, height, data }
+    }
+}
+
+fn psnr(reference: &Frame, capture: &Frame, plane: usize) -> f64 {
+    let (width, height) = reference.plane_size(plane);
+    let mut squared_error = 0u64;
+    for y in 0..height {
+        let a = &reference.planes[plane][y * reference.strides[plane]..][..width];
+        let b = &capture.planes[plane][y * capture.strides[plane]..][..width];
+        for (&a, &b) in a.iter().zip(b) {
+            let difference = a as i64 - b as i64;
+            squared_error += (difference * difference) as u64;
+        }
+    }
+
+    if squared_error == 0 {
+        return MAX_PSNR;
+    }
+    let mse = squared_error as f64 / (width * height) as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_PSNR)
+}
+
+/// Mean SSIM and mean contrast-structure term over all windows. Images
+/// smaller than a window are a single window.
+fn ssim_components(a: &Luma, b: &Luma) -> (f64, f64) {
+    let window_width = SSIM_WINDOW.min(a.width);
+    let window_height = SSIM_WINDOW.min(a.height);
+    let (mut ssim, mut cs, mut windows) = (0.0, 0.0, 0);
+
+    let mut y = 0;
+    while y + window_height <= a.height {
+        let mut x = 0;
+        while x + window_width <= a.width {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for wy in y..y + window_height {
+                for wx in x..x + window_width {
+                    let (va, vb) = (a.data[wy * a.width + wx], b.data[wy * b.width + wx]);
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            let luminance = (2.0 * mean_a * mean_b + SSIM_C1) / (mean_a * mean_a + mean_b * mean_b + SSIM_C1);
+            let contrast_structure = (2.0 * covariance + SSIM_C2) / (variance_a + variance_b + SSIM_C2);
+            ssim += luminance * contrast_structure;
+            cs += contrast_structure;
+            windows += 1;
+
+            x += SSIM_STEP;
+        }
+        y += SSIM_STEP;
+    }
+
+    (ssim / windows as f64, cs / windows as f64)
+}
+
+fn ms_ssim(reference: &Frame, capture: &Frame) -> f64 {
+    let mut a = Luma::from_frame(reference);
+    let mut b = Luma::from_frame(capture);
+
+    let mut ms_ssim = 1.0;
+    for (scale, &weight) in MS_SSIM_WEIGHTS.iter().enumerate() {
+        let (scale_ssim, cs) = ssim_components(&a, &b);
+
+        // The coarsest scale (or the last one the frame is big enough for)
+        // contributes its luminance term too
+        let last = scale == MS_SSIM_WEIGHTS.len() - 1 || a.width < 2 * SSIM_WINDOW || a.height < 2 * SSIM_WINDOW;
+        // Negative structure terms would make the product meaningless
+        ms_ssim *= (if last { scale_ssim } else { cs }).max(0.0).powf(weight);
+        if last {
+            break;
+        }
+
+        a = a.downscale();
+        b = b.downscale();
+    }
+
+    ms_ssim
+}
+
+pub struct Psnr;
+
+impl Metric for Psnr {
+    fn measure(&mut self, pair: &AlignedPair) -> Vec<(String, f64)> {
+        ["psnr_y", "psnr_u", "psnr_v"].iter()
+            .enumerate()
+            .map(|(plane, name)| (name.to_string(), psnr(pair.reference, pair.capture, plane)))
+            .collect()
+    }
+}
+
+pub struct Ssim;
+
+impl Metric for Ssim {
+    fn measure(&mut self, pair: &AlignedPair) -> Vec<(String, f64)> {
+        let (ssim, _) = ssim_components(&Luma::from_frame(pair.reference), &Luma::from_frame(pair.capture));
+        vec![("ssim".to_owned(), ssim)]
+    }
+}
+
+pub struct MsSsim;
+
+impl Metric for MsSsim {
+    fn measure(&mut self, pair: &AlignedPair) -> Vec<(String, f64)> {
+        vec![("ms_ssim".to_owned(), ms_ssim(pair.reference, pair.capture))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packed frame with the luma `luma(x, y)` and grey chroma
+    fn frame<F: Fn(usize, usize) -> u8>(width: usize, height: usize, luma: F) -> Frame {
+        let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+        let y = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| luma(x, y)).collect();
+        let chroma = vec![128; chroma_width * chroma_height];
+        Frame {
+            width,
+            height,
+            planes: [y, chroma.clone(), chroma],
+            strides: [width, chroma_width, chroma_width],
+        }
+    }
+
+    fn gradient(x: usize, y: usize) -> u8 {
+        ((x * 7 + y * 3) % 256) as u8
+    }
+
+    #[test]
+    fn psnr_of_identical_frames_is_the_maximum() {
+        let a = frame(16, 16, gradient);
+        for plane in 0..3 {
+            assert_eq!(psnr(&a, &a.clone(), plane), MAX_PSNR);
+        }
+    }
+
+    #[test]
+    fn psnr_of_a_known_difference() {
+        let a = frame(16, 16, |_, _| 100);
+        let b = frame(16, 16, |_, _| 110);
+        // Every sample is 10 off, so the MSE is 100
+        let expected = 10.0 * (255.0f64 * 255.0 / 100.0).log10();
+        assert!((psnr(&a, &b, 0) - expected).abs() < 1e-9);
+        assert_eq!(psnr(&a, &b, 1), MAX_PSNR);
+    }
+
+    #[test]
+    fn psnr_ignores_stride_padding() {
+        let a = frame(6, 4, gradient);
+        let mut b = a.clone();
+        b.planes[0] = a.planes[0].chunks(6).flat_map(|row| row.iter().cloned().chain(vec![255, 0])).collect();
+        b.strides[0] = 8;
+        assert_eq!(psnr(&a, &b, 0), MAX_PSNR);
+    }
+
+    #[test]
+    fn ssim_of_identical_frames_is_one() {
+        let a = frame(32, 24, gradient);
+        let mut ssim = Ssim;
+        let scores = ssim.measure(&AlignedPair { id: "f:0", reference: &a, capture: &a });
+        assert_eq!(scores[0].0, "ssim");
+        assert!((scores[0].1 - 1.0).abs() < 1e-9);
+        assert!((ms_ssim(&a, &a) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ssim_of_different_frames_is_lower() {
+        let a = frame(32, 24, gradient);
+        let b = frame(32, 24, |x, y| if (x + y) % 2 == 0 { 0 } else { 255 });
+        let (ssim, _) = ssim_components(&Luma::from_frame(&a), &Luma::from_frame(&b));
+        assert!(ssim < 0.5);
+        assert!(ms_ssim(&a, &b) < 1.0);
+    }
+}
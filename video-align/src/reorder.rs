@@ -0,0 +1,113 @@
+use failure::Error;
+
+use std::collections::HashMap;
+
+use config::Config;
+use frames;
+use Written;
+
+/// A capture frame that isn't written where it arrived
+#[derive(Debug, Serialize)]
+pub struct Reordering {
+    pub id: Option<String>,
+    /// Position among the written capture frames
+    pub arrival_index: usize,
+    /// Position in the aligned output, None if the frame was dropped because
+    /// it has no id of the reference
+    pub output_index: Option<usize>,
+}
+
+/// Arrival indices of the capture frames in the order of `reference_ids`,
+/// and the frames that don't stay where they arrived
+fn plan(ids: &[Option<String>], reference_ids: &[String]) -> (Vec<usize>, Vec<Reordering>) {
+    let positions: HashMap<&str, usize> = reference_ids.iter()
+        .enumerate()
+        .map(|(position, id)| (id.as_str(), position))
+        .collect();
+    let position = |id: &Option<String>| id.as_ref().and_then(|id| positions.get(id.as_str()).cloned());
+
+    // Stable, so frames that can't be told apart keep their order
+    let mut order: Vec<(usize, usize)> = ids.iter()
+        .enumerate()
+        .filter_map(|(arrival_index, id)| position(id).map(|position| (arrival_index, position)))
+        .collect();
+    order.sort_by_key(|&(_, position)| position);
+    let output_indices: HashMap<usize, usize> = order.iter()
+        .enumerate()
+        .map(|(output_index, &(arrival_index, _))| (arrival_index, output_index))
+        .collect();
+
+    // A frame arriving after one it precedes in the reference is the one
+    // that moved, the frames following it only shift
+    let mut reordered = Vec::new();
+    let mut latest = None;
+    for (arrival_index, id) in ids.iter().enumerate() {
+        match position(id) {
+            Some(position) if latest.map_or(false, |latest| position < latest) => {
+                reordered.push(Reordering { id: id.clone(), arrival_index, output_index: output_indices.get(&arrival_index).cloned() });
+            }
+            Some(position) => latest = Some(position),
+            None => reordered.push(Reordering { id: id.clone(), arrival_index, output_index: None }),
+        }
+    }
+
+    (order.into_iter().map(|(arrival_index, _)| arrival_index).collect(), reordered)
+}
+
+/// Rewrites the capture output with its frames in the order of
+/// `reference_ids`, and `capture`'s ids with it
+pub fn reorder(config: &Config, capture: &mut Written, reference_ids: &[String]) -> Result<Vec<Reordering>, Error> {
+    let (order, reordered) = plan(&capture.ids, reference_ids);
+
+    if !reordered.is_empty() {
+        frames::reorder(&config.capture_output, config.format, capture.layout, &order)?;
+        capture.ids = order.iter().map(|&arrival_index| capture.ids[arrival_index].clone()).collect();
+    }
+
+    Ok(reordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<Option<String>> {
+        ids.iter().map(|id| if id.is_empty() { None } else { Some(id.to_string()) }).collect()
+    }
+
+    fn reference() -> Vec<String> {
+        vec!["f:0", "f:1", "f:2", "f:3"].into_iter().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn frames_in_order_stay() {
+        let (order, reordered) = plan(&ids(&["f:0", "f:1", "f:3"]), &reference());
+        assert_eq!(order, vec![0, 1, 2]);
+        assert!(reordered.is_empty());
+    }
+
+    #[test]
+    fn late_frame_is_moved_back() {
+        let (order, reordered) = plan(&ids(&["f:0", "f:2", "f:3", "f:1"]), &reference());
+        assert_eq!(order, vec![0, 3, 1, 2]);
+        assert_eq!(reordered.len(), 1);
+        assert_eq!(reordered[0].id, Some("f:1".to_owned()));
+        assert_eq!((reordered[0].arrival_index, reordered[0].output_index), (3, Some(1)));
+    }
+
+    #[test]
+    fn frames_without_a_reference_id_are_dropped() {
+        let (order, reordered) = plan(&ids(&["f:0", "", "f:9", "f:1"]), &reference());
+        assert_eq!(order, vec![0, 3]);
+        let dropped: Vec<_> = reordered.iter().map(|r| (r.id.clone(), r.arrival_index, r.output_index)).collect();
+        assert_eq!(dropped, vec![(None, 1, None), (Some("f:9".to_owned()), 2, None)]);
+    }
+
+    #[test]
+    fn repeats_keep_their_arrival_order() {
+        let (order, reordered) = plan(&ids(&["f:0", "f:1", "f:0"]), &reference());
+        assert_eq!(order, vec![0, 2, 1]);
+        assert_eq!(reordered.len(), 1);
+        assert_eq!((reordered[0].arrival_index, reordered[0].output_index), (2, Some(1)));
+    }
+}
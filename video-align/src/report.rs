@@ -0,0 +1,398 @@
+use failure::Error;
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use config::Config;
+use reorder::Reordering;
+
+/// Segment a frame belongs to, from the prefix of its id
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Segment {
+    Start,
+    Content,
+    End,
+}
+
+/// First and last of consecutive frames
+#[derive(Debug, Serialize)]
+struct Range<T> {
+    first: T,
+    last: T,
+    frames: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Repeat {
+    id: String,
+    /// Times the id was captured
+    count: usize,
+}
+
+/// Frames captured earlier than a frame that comes after them in the reference
+#[derive(Debug, Serialize)]
+struct OutOfOrderRun {
+    /// Position of the run's first frame in the capture
+    capture_index: usize,
+    first: String,
+    last: String,
+    frames: usize,
+}
+
+/// A code that isn't the id of a prepared frame
+#[derive(Debug, Serialize)]
+struct Unreadable {
+    capture_index: usize,
+    codes: Vec<String>,
+}
+
+/// Content frames that aren't in the reference
+#[derive(Debug, Serialize)]
+struct Extra {
+    capture_index: usize,
+    id: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Count {
+    frames: usize,
+    /// Of the reference's content frames for delivered and missing, of the
+    /// captured frames otherwise
+    percent: f64,
+}
+
+impl Count {
+    fn new(frames: usize, total: usize) -> Count {
+        Count { frames, percent: if total == 0 { 0.0 } else { 100.0 * frames as f64 / total as f64 } }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Totals {
+    reference_frames: usize,
+    capture_frames: usize,
+    start_frames: usize,
+    content_frames: usize,
+    end_frames: usize,
+    delivered: Count,
+    missing: Count,
+    repeated: Count,
+    out_of_order: Count,
+    no_code: Count,
+    unreadable: Count,
+    extra: Count,
+}
+
+/// How the capture compares with the reference, frame by frame
+#[derive(Debug, Serialize)]
+pub struct Report {
+    totals: Totals,
+    /// Reference frames that were never captured
+    missing: Vec<Range<String>>,
+    repeated: Vec<Repeat>,
+    out_of_order: Vec<OutOfOrderRun>,
+    /// Captured frames without any code, by capture index
+    no_code: Vec<Range<usize>>,
+    unreadable: Vec<Unreadable>,
+    extra: Vec<Extra>,
+    /// Capture indices of the pre-roll and post-roll frames
+    start_segment: Vec<Range<usize>>,
+    end_segment: Vec<Range<usize>>,
+    /// Capture frames written elsewhere than they arrived, or dropped, to
+    /// put the aligned output in the reference's order
+    reordered: Vec<Reordering>,
+}
+
+fn segment(config: &Config, code: &str) -> Option<Segment> {
+    if code.starts_with(&config.prefix) {
+        Some(Segment::Content)
+    } else if code.starts_with(&config.start_prefix) {
+        Some(Segment::Start)
+    } else if code.starts_with(&config.end_prefix) {
+        Some(Segment::End)
+    } else {
+        None
+    }
+}
+
+/// The first code of a frame that is an id, if any
+fn first_id(config: &Config, codes: &[String]) -> Option<(Segment, String)> {
+    codes.iter().filter_map(|code| segment(config, code).map(|s| (s, code.clone()))).next()
+}
+
+/// Ids of the content frames, from the codes read from every frame
+pub fn content_ids(config: &Config, codes: &[Vec<String>]) -> Vec<String> {
+    codes.iter()
+        .filter_map(|codes| first_id(config, codes))
+        .filter(|&(segment, _)| segment == Segment::Content)
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// Groups sorted indices into ranges of consecutive ones
+fn ranges(indices: &[usize]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for &index in indices {
+        match ranges.last_mut() {
+            Some(ref mut range) if range.last + 1 == index => {
+                range.last = index;
+                range.frames += 1;
+                continue;
+            }
+            _ => (),
+        }
+        ranges.push(Range { first: index, last: index, frames: 1 });
+    }
+    ranges
+}
+
+impl Report {
+    /// Compares the codes read from every captured frame with the content
+    /// ids of the reference, in their order
+    pub fn new(config: &Config, reference: &[Vec<String>], capture: &[Vec<String>]) -> Report {
+        let reference_ids = content_ids(config, reference);
+        let positions: HashMap<&str, usize> = reference_ids.iter()
+            .enumerate()
+            .map(|(position, id)| (id.as_str(), position))
+            .collect();
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut repeated_order = Vec::new();
+        let (mut start, mut content, mut end, mut no_code) = (Vec::new(), 0, Vec::new(), Vec::new());
+        let mut unreadable = Vec::new();
+        let mut extra = Vec::new();
+        let mut out_of_order: Vec<OutOfOrderRun> = Vec::new();
+        let mut latest = None;
+        let mut in_run = false;
+
+        for (index, codes) in capture.iter().enumerate() {
+            let (segment, id) = match first_id(config, codes) {
+                Some(found) => found,
+                None if codes.is_empty() => {
+                    no_code.push(index);
+                    continue;
+                }
+                None => {
+                    unreadable.push(Unreadable { capture_index: index, codes: codes.clone() });
+                    continue;
+                }
+            };
+
+            match segment {
+                Segment::Start => start.push(index),
+                Segment::End => end.push(index),
+                Segment::Content => {
+                    content += 1;
+                    let count = counts.entry(id.clone()).or_insert(0);
+                    *count += 1;
+                    if *count == 2 {
+                        repeated_order.push(id.clone());
+                    }
+                    // Repeats are counted once, where they first appeared
+                    if *count > 1 {
+                        continue;
+                    }
+
+                    let position = match positions.get(id.as_str()) {
+                        Some(&position) => position,
+                        None => {
+                            extra.push(Extra { capture_index: index, id });
+                            continue;
+                        }
+                    };
+                    if latest.map_or(false, |latest| position < latest) {
+                        match out_of_order.last_mut() {
+                            Some(ref mut run) if in_run => {
+                                run.last = id;
+                                run.frames += 1;
+                            }
+                            _ => out_of_order.push(OutOfOrderRun { capture_index: index, first: id.clone(), last: id, frames: 1 }),
+                        }
+                        in_run = true;
+                    } else {
+                        latest = Some(position);
+                        in_run = false;
+                    }
+                }
+            }
+        }
+
+        let missing_positions: Vec<usize> = reference_ids.iter()
+            .enumerate()
+            .filter(|&(_, id)| !counts.contains_key(id))
+            .map(|(position, _)| position)
+            .collect();
+        let missing: Vec<Range<String>> = ranges(&missing_positions).into_iter()
+            .map(|range| Range { first: reference_ids[range.first].clone(), last: reference_ids[range.last].clone(), frames: range.frames })
+            .collect();
+
+        let repeated: Vec<Repeat> = repeated_order.into_iter()
+            .map(|id| Repeat { count: counts[&id], id })
+            .collect();
+
+        let (reference_frames, capture_frames) = (reference_ids.len(), capture.len());
+        let delivered = reference_ids.iter().filter(|id| counts.contains_key(*id)).count();
+        let totals = Totals {
+            reference_frames,
+            capture_frames,
+            start_frames: start.len(),
+            content_frames: content,
+            end_frames: end.len(),
+            delivered: Count::new(delivered, reference_frames),
+            missing: Count::new(missing_positions.len(), reference_frames),
+            repeated: Count::new(repeated.iter().map(|repeat| repeat.count - 1).sum(), capture_frames),
+            out_of_order: Count::new(out_of_order.iter().map(|run| run.frames).sum(), capture_frames),
+            no_code: Count::new(no_code.len(), capture_frames),
+            unreadable: Count::new(unreadable.len(), capture_frames),
+            extra: Count::new(extra.len(), capture_frames),
+        };
+
+        Report {
+            totals,
+            missing,
+            repeated,
+            out_of_order,
+            no_code: ranges(&no_code),
+            unreadable,
+            extra,
+            start_segment: ranges(&start),
+            end_segment: ranges(&end),
+            reordered: Vec::new(),
+        }
+    }
+
+    pub fn set_reordered(&mut self, reordered: Vec<Reordering>) {
+        self.reordered = reordered;
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        let totals = &self.totals;
+        let line = |name: &str, count: &Count| println!("  {:13} {:6} ({:.2}%)", name, count.frames, count.percent);
+
+        println!("Captured {} frames: {} pre-roll, {} content, {} post-roll",
+                 totals.capture_frames, totals.start_frames, totals.content_frames, totals.end_frames);
+        println!("Of the {} reference frames:", totals.reference_frames);
+        line("delivered", &totals.delivered);
+        line("missing", &totals.missing);
+        println!("Of the captured frames:");
+        line("repeated", &totals.repeated);
+        line("out of order", &totals.out_of_order);
+        line("no code", &totals.no_code);
+        line("unreadable", &totals.unreadable);
+        line("extra", &totals.extra);
+
+        for range in &self.missing {
+            println!("  missing {} to {} ({} frames)", range.first, range.last, range.frames);
+        }
+        for run in &self.out_of_order {
+            println!("  out of order at capture frame {}: {} to {} ({} frames)", run.capture_index, run.first, run.last, run.frames);
+        }
+        for reordering in &self.reordered {
+            let id = reordering.id.as_ref().map_or("frame without id", String::as_str);
+            match reordering.output_index {
+                Some(output_index) => println!("  moved {} from {} to {}", id, reordering.arrival_index, output_index),
+                None => println!("  dropped {} at {}", id, reordering.arrival_index),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Cropping, MetricsFormat, OutputFormat};
+
+    fn config() -> Config {
+        Config {
+            reference: "file:///reference.mp4".to_owned(),
+            capture: "file:///capture.mp4".to_owned(),
+            cropping: Cropping { left: 0, top: 0, right: 0, bottom: 0 },
+            reference_output: "reference.y4m".to_owned(),
+            capture_output: "capture.y4m".to_owned(),
+            format: OutputFormat::Y4m,
+            metrics_format: MetricsFormat::None,
+            metrics: Vec::new(),
+            reorder: true,
+            prefix: "f:".to_owned(),
+            start_prefix: "s:".to_owned(),
+            end_prefix: "e:".to_owned(),
+        }
+    }
+
+    /// One code per frame, none for an empty string
+    fn codes(ids: &[&str]) -> Vec<Vec<String>> {
+        ids.iter()
+            .map(|id| if id.is_empty() { Vec::new() } else { vec![id.to_string()] })
+            .collect()
+    }
+
+    fn reference() -> Vec<Vec<String>> {
+        codes(&["s:0", "f:0", "f:1", "f:2", "f:3", "f:4", "e:0"])
+    }
+
+    #[test]
+    fn repeats_are_counted_once_each() {
+        let report = Report::new(&config(), &reference(), &codes(&["f:0", "f:1", "f:1", "f:1", "f:2", "f:3", "f:3", "f:4"]));
+
+        assert_eq!(report.repeated.len(), 2);
+        assert_eq!((report.repeated[0].id.as_str(), report.repeated[0].count), ("f:1", 3));
+        assert_eq!((report.repeated[1].id.as_str(), report.repeated[1].count), ("f:3", 2));
+        assert_eq!(report.totals.repeated.frames, 3);
+        assert_eq!(report.totals.delivered.frames, 5);
+        assert!(report.out_of_order.is_empty());
+        assert!(report.missing.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_frames_form_runs() {
+        let report = Report::new(&config(), &reference(), &codes(&["f:0", "f:3", "f:1", "f:2", "f:4"]));
+
+        assert_eq!(report.out_of_order.len(), 1);
+        let run = &report.out_of_order[0];
+        assert_eq!((run.capture_index, run.first.as_str(), run.last.as_str(), run.frames), (2, "f:1", "f:2", 2));
+        assert_eq!(report.totals.out_of_order.frames, 2);
+        assert!(report.missing.is_empty());
+    }
+
+    #[test]
+    fn missing_frames_are_grouped_into_ranges() {
+        let report = Report::new(&config(), &reference(), &codes(&["f:0", "f:3"]));
+
+        let missing: Vec<_> = report.missing.iter()
+            .map(|range| (range.first.as_str(), range.last.as_str(), range.frames))
+            .collect();
+        assert_eq!(missing, vec![("f:1", "f:2", 2), ("f:4", "f:4", 1)]);
+        assert_eq!(report.totals.missing.frames, 3);
+        assert!((report.totals.missing.percent - 60.0).abs() < 1e-9);
+        assert_eq!(report.totals.delivered.frames, 2);
+    }
+
+    #[test]
+    fn frames_without_a_content_id_are_classified() {
+        let report = Report::new(&config(), &reference(), &codes(&["s:0", "s:1", "", "junk", "f:0", "f:9", "e:0"]));
+
+        assert_eq!(report.totals.start_frames, 2);
+        assert_eq!(report.totals.content_frames, 2);
+        assert_eq!(report.totals.end_frames, 1);
+        assert_eq!(report.start_segment.len(), 1);
+        assert_eq!((report.start_segment[0].first, report.start_segment[0].last), (0, 1));
+        assert_eq!(report.no_code.len(), 1);
+        assert_eq!(report.no_code[0].first, 2);
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].capture_index, 3);
+        assert_eq!(report.extra.len(), 1);
+        assert_eq!((report.extra[0].capture_index, report.extra[0].id.as_str()), (5, "f:9"));
+    }
+
+    #[test]
+    fn content_ids_skip_other_segments() {
+        assert_eq!(content_ids(&config(), &reference()), vec!["f:0", "f:1", "f:2", "f:3", "f:4"]);
+    }
+}
@@ -0,0 +1,191 @@
+use failure::Error;
+use glob;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use config::{self, Config};
+use interrupt;
+use pool;
+use prepare;
+
+#[derive(Debug, Fail)]
+#[fail(display = "No inputs found in {}", _0)]
+struct NoInputs(String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} of {} inputs failed", _0, _1)]
+pub struct BatchFailed(usize, usize);
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} and {} would both be written to {}, rename one of them", _0, _1, _2)]
+struct OutputCollision(String, String, String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} would be overwritten by its own output, use another output directory", _0)]
+struct OutputIsInput(String);
+
+const PLAYLIST_EXTENSIONS: [&str; 3] = ["txt", "m3u", "m3u8"];
+
+fn is_playlist(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| PLAYLIST_EXTENSIONS.contains(&e))
+        .unwrap_or(false)
+}
+
+/// One path or URI per line, relative paths being relative to the playlist
+fn read_playlist(path: &Path) -> Result<Vec<String>, Error> {
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut inputs = Vec::new();
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.contains("://") || Path::new(line).is_absolute() {
+            inputs.push(line.to_owned());
+        } else {
+            inputs.push(base.join(line).to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Expands a directory, glob pattern or playlist file into the inputs it names
+fn collect_inputs(spec: &str) -> Result<Vec<String>, Error> {
+    let path = Path::new(spec);
+
+    let mut inputs = if path.is_dir() {
+        let mut inputs = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?.path();
+            if entry.is_file() && !is_playlist(&entry) {
+                inputs.push(entry.to_string_lossy().into_owned());
+            }
+        }
+        inputs
+    } else if is_playlist(path) {
+        read_playlist(path)?
+    } else {
+        let mut inputs = Vec::new();
+        for entry in glob::glob(spec)? {
+            let entry = entry?;
+            if entry.is_file() {
+                inputs.push(entry.to_string_lossy().into_owned());
+            }
+        }
+        inputs
+    };
+
+    if inputs.is_empty() {
+        Err(NoInputs(spec.to_owned()))?;
+    }
+    inputs.sort();
+
+    Ok(inputs)
+}
+
+fn output_path(output_dir: &str, input: &str, extension: &str) -> PathBuf {
+    let stem = Path::new(input)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_owned());
+
+    Path::new(output_dir).join(format!("{}.{}", stem, extension))
+}
+
+/// Output path of every input. Inputs run in parallel, so no two of them
+/// may write to the same output (and manifest), and none may overwrite
+/// itself.
+fn output_paths(output_dir: &str, inputs: &[String], extension: &str) -> Result<Vec<PathBuf>, Error> {
+    // The directory exists by now, the outputs don't necessarily
+    let canonical_dir = fs::canonicalize(output_dir)?;
+    let mut written_by: HashMap<PathBuf, &str> = HashMap::new();
+    let mut outputs = Vec::new();
+
+    for input in inputs {
+        let output = output_path(output_dir, input, extension);
+        let canonical_output = canonical_dir.join(output.file_name().unwrap());
+        if let Some(other) = written_by.insert(canonical_output.clone(), input) {
+            Err(OutputCollision(other.to_owned(), input.clone(), output.display().to_string()))?;
+        }
+        let canonical_input = config::to_path(input).ok().and_then(|path| fs::canonicalize(path).ok());
+        if canonical_input.as_ref() == Some(&canonical_output) {
+            Err(OutputIsInput(input.clone()))?;
+        }
+        outputs.push(output);
+    }
+
+    Ok(outputs)
+}
+
+struct Outcome {
+    input: String,
+    output: PathBuf,
+    elapsed: Duration,
+    result: Result<(), String>,
+}
+
+/// Prepares every input named by `config.input` into the `config.output`
+/// directory, running `config.jobs` pipelines at a time
+pub fn run(config: &Config) -> Result<(), Error> {
+    let inputs = collect_inputs(&config.input)?;
+    let total = inputs.len();
+    fs::create_dir_all(&config.output)?;
+
+    let outputs = output_paths(&config.output, &inputs, config.encoding.extension())?;
+    let mut jobs = Vec::new();
+    for (input, output) in inputs.into_iter().zip(outputs) {
+        let mut job = config.clone();
+        job.output = output.to_string_lossy().into_owned();
+        job.input = config::to_uri(&input).map_err(|e| format_err!("{}", e))?;
+        jobs.push((input, job));
+    }
+
+    // Inputs not started yet when interrupted are skipped, the running ones
+    // finish their output
+    let outcomes: Vec<Outcome> = pool::run(jobs, config.jobs, |(input, job)| {
+        println!("Preparing {}", input);
+        let start = Instant::now();
+        let result = prepare(&job).map_err(|e| e.to_string());
+        Outcome {
+            input,
+            output: PathBuf::from(&job.output),
+            elapsed: start.elapsed(),
+            result,
+        }
+    }).into_iter().flatten().collect();
+
+    println!();
+    println!("Summary:");
+    let mut failed = 0;
+    for outcome in outcomes.iter() {
+        let seconds = outcome.elapsed.as_secs() as f64 + outcome.elapsed.subsec_nanos() as f64 / 1e9;
+        match outcome.result {
+            Ok(()) => println!("  ok     {} -> {} ({:.1}s)", outcome.input, outcome.output.display(), seconds),
+            Err(ref e) => {
+                failed += 1;
+                println!("  FAILED {} ({:.1}s): {}", outcome.input, seconds, e);
+            }
+        }
+    }
+    let skipped = total - outcomes.len();
+    if skipped > 0 {
+        println!("{} prepared, {} failed, {} skipped", outcomes.len() - failed, failed, skipped);
+        Err(interrupt::Interrupted)?;
+    }
+    println!("{} prepared, {} failed", total - failed, failed);
+
+    if failed > 0 {
+        Err(BatchFailed(failed, total))?;
+    }
+
+    Ok(())
+}
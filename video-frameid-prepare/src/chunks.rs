@@ -0,0 +1,252 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use config::{self, Config, SegmentLength};
+use discover;
+use finish_output;
+use interrupt::{self, Interrupted};
+use main_loop;
+use make_element;
+use manifest::Manifest;
+use package;
+use pool;
+use run_prepare;
+use trim::{Numbering, Position, Trim};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Profile {} has no container the chunks could be joined in", _0)]
+struct NoContainer(&'static str);
+
+/// Stream times of the keyframes of the input's video, found without
+/// decoding it. Trim positions are in stream time as well.
+fn find_keyframes(uri: &str) -> Result<Vec<u64>, Error> {
+    let pipeline = gst::Pipeline::new(None);
+    let src = make_element("urisourcebin")?;
+    let parsebin = make_element("parsebin")?;
+
+    src.set_property("uri", &uri)?;
+    pipeline.add_many(&[&src, &parsebin])?;
+
+    let parsebin_clone = parsebin.clone();
+    src.connect_pad_added(move |_, src_pad| {
+        let sink_pad = parsebin_clone.get_static_pad("sink").unwrap();
+        if !sink_pad.is_linked() {
+            assert_eq!(src_pad.link(&sink_pad), gst::PadLinkReturn::Ok);
+        }
+    });
+
+    let keyframes = Arc::new(Mutex::new(Vec::new()));
+    let keyframes_clone = keyframes.clone();
+    let pipeline_clone = pipeline.clone();
+    parsebin.connect_pad_added(move |_, src_pad| {
+        let caps = src_pad.get_current_caps().unwrap();
+        let is_video = caps.get_structure(0).unwrap().get_name().starts_with("video/");
+
+        let sink = gst::ElementFactory::make("fakesink", None).unwrap();
+        pipeline_clone.add(&sink).unwrap();
+        sink.sync_state_with_parent().unwrap();
+        let sink_pad = sink.get_static_pad("sink").unwrap();
+
+        if is_video && keyframes_clone.lock().unwrap().is_empty() {
+            let keyframes = keyframes_clone.clone();
+            let segment = Mutex::new(None::<gst::FormattedSegment<gst::ClockTime>>);
+            sink_pad.add_probe(gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+                match info.data {
+                    Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                        if buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                            return gst::PadProbeReturn::Ok;
+                        }
+                        let segment = segment.lock().unwrap();
+                        let pts = segment.as_ref().and_then(|segment| segment.to_stream_time(buffer.get_pts()).nseconds());
+                        if let Some(pts) = pts {
+                            keyframes.lock().unwrap().push(pts);
+                        }
+                    }
+                    Some(gst::PadProbeData::Event(ref event)) => {
+                        if let gst::EventView::Segment(e) = event.view() {
+                            *segment.lock().unwrap() = e.get_segment().downcast_ref::<gst::ClockTime>().cloned();
+                        }
+                    }
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+
+        assert_eq!(src_pad.link(&sink_pad), gst::PadLinkReturn::Ok);
+    });
+
+    main_loop(pipeline, None)?;
+
+    let mut keyframes = keyframes.lock().unwrap().clone();
+    keyframes.sort();
+    Ok(keyframes)
+}
+
+/// First frame of every chunk: the first keyframe at least `chunk_frames`
+/// after the start of the previous chunk. Frames are counted from stream
+/// time 0, like the seeks to the chunks.
+fn chunk_starts(keyframes: &[u64], framerate: gst::Fraction, chunk_frames: u64) -> Vec<u64> {
+    let to_frame = |pts: u64| {
+        (pts as f64 * *framerate.numer() as f64
+            / (*framerate.denom() as f64 * 1_000_000_000f64)).round() as u64
+    };
+
+    let mut starts = vec![0];
+    for frame in keyframes.iter().map(|&pts| to_frame(pts)) {
+        if frame >= starts[starts.len() - 1] + chunk_frames {
+            starts.push(frame);
+        }
+    }
+    starts
+}
+
+/// Joins the encoded chunks into `config.output` without re-encoding them
+fn join(config: &Config, chunks: &[String]) -> Result<(), Error> {
+    let has_audio = discover::discover(&config::to_uri(&chunks[0]).map_err(|e| format_err!("{}", e))?)?
+        .audio
+        .is_some();
+
+    let pipeline = gst::Pipeline::new(None);
+    let concat = make_element("concat")?;
+    let mux = config.encoding.make_muxer()?.ok_or_else(|| NoContainer(config.encoding.profile.name()))?;
+    let sink = make_element("filesink")?;
+
+    sink.set_property("location", &config.output)?;
+
+    pipeline.add_many(&[&concat, &mux, &sink])?;
+    gst::Element::link_many(&[&concat, &mux, &sink])?;
+
+    let audio_concat = if has_audio {
+        let audio_concat = make_element("concat")?;
+        pipeline.add(&audio_concat)?;
+        audio_concat.link(&mux)?;
+        Some(audio_concat)
+    } else {
+        None
+    };
+
+    // Every chunk went through the same encoder settings, so their caps (and
+    // parameter sets) match and the muxer sees a single stream. concat plays
+    // its pads in the order they were requested, while the chunks' pads
+    // appear in any order.
+    for chunk in chunks {
+        let video_pad = Mutex::new(concat.get_request_pad("sink_%u"));
+        let audio_pad = Mutex::new(audio_concat.as_ref().and_then(|concat| concat.get_request_pad("sink_%u")));
+        package::add_parsed_source(&pipeline, chunk, move |name| {
+            if name.starts_with("video/") {
+                video_pad.lock().unwrap().take()
+            } else if name.starts_with("audio/") {
+                audio_pad.lock().unwrap().take()
+            } else {
+                None
+            }
+        })?;
+    }
+
+    main_loop(pipeline, None)
+}
+
+/// Prepares `config.input` in chunks of `config.chunk_duration` seconds,
+/// `config.jobs` at a time, then joins them. Chunks keep the frame numbers
+/// of the input, so the ids go on from one chunk to the next.
+pub fn run(config: &Config) -> Result<(), Error> {
+    let chunk_duration = config.chunk_duration.unwrap();
+    let media = discover::discover(&config.input)?;
+    let framerate = config.conform.target(&config.input, &media.video)?.framerate;
+    let chunk_frames = (chunk_duration * *framerate.numer() as f64 / *framerate.denom() as f64).ceil() as u64;
+
+    // Chunks start at keyframes so their seeks don't decode frames they then
+    // throw away
+    let starts = chunk_starts(&find_keyframes(&config.input)?, framerate, chunk_frames.max(1));
+    let directory = format!("{}.chunks", config.output);
+    fs::create_dir_all(&directory)?;
+
+    let mut jobs = Vec::new();
+    for (n, &start) in starts.iter().enumerate() {
+        let last = n == starts.len() - 1;
+        let mut job = config.clone();
+        job.chunk_duration = None;
+        job.verify = false;
+        job.manifest_format = "none".to_owned();
+        job.trim = Trim {
+            start: Some(Position::Frame(start)),
+            // The last chunk goes on to the end, whatever the container says
+            end: if last { None } else { Some(Position::Frame(starts[n + 1])) },
+            numbering: Numbering::Source,
+        };
+        if n > 0 {
+            job.preroll = SegmentLength::Frames(0);
+        }
+        if !last {
+            job.postroll = SegmentLength::Frames(0);
+        }
+        job.output = Path::new(&directory)
+            .join(format!("chunk{:05}.{}", n, config.encoding.extension()))
+            .to_string_lossy()
+            .into_owned();
+        jobs.push(job);
+    }
+
+    println!("Preparing {} in {} chunks", config.input, starts.len());
+
+    let results = pool::run(jobs, config.jobs, |job| run_prepare(&job).map(|manifest| (job.output.clone(), manifest)));
+    if interrupt::is_interrupted() {
+        Err(Interrupted)?;
+    }
+
+    let mut outputs = Vec::new();
+    let mut manifests = Vec::new();
+    for (n, result) in results.into_iter().enumerate() {
+        let (output, manifest) = result.ok_or(Interrupted)?.map_err(|e| format_err!("Chunk {} failed: {}", n, e))?;
+        outputs.push(output);
+        manifests.push(manifest);
+    }
+
+    join(config, &outputs)?;
+    fs::remove_dir_all(&directory)?;
+
+    finish_output(config, &Manifest::join(&manifests))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timestamps of `frames` at 25 fps, starting at `origin`
+    fn pts(origin: u64, frames: &[u64]) -> Vec<u64> {
+        frames.iter().map(|frame| origin + frame * 40_000_000).collect()
+    }
+
+    #[test]
+    fn chunks_start_at_the_first_keyframe_far_enough() {
+        let keyframes = pts(0, &[0, 30, 50, 100, 120, 250]);
+        assert_eq!(chunk_starts(&keyframes, gst::Fraction::new(25, 1), 50), vec![0, 50, 100, 250]);
+    }
+
+    #[test]
+    fn chunk_starts_count_from_stream_time_zero() {
+        // The first keyframe is at frame 25, so the first chunk starts
+        // before it and seeks there
+        let keyframes = pts(1_000_000_000, &[0, 25, 50, 75]);
+        assert_eq!(chunk_starts(&keyframes, gst::Fraction::new(25, 1), 50), vec![0, 50, 100]);
+    }
+
+    #[test]
+    fn chunk_starts_round_to_the_nearest_frame() {
+        // 29.97 fps, keyframes every 50 frames, whose timestamps are truncated
+        let keyframes: Vec<u64> = (0..4).map(|n| n * 50 * 1_001_000_000_000 / 30_000).collect();
+        assert_eq!(chunk_starts(&keyframes, gst::Fraction::new(30_000, 1001), 100), vec![0, 100]);
+    }
+
+    #[test]
+    fn input_without_keyframes_is_one_chunk() {
+        assert_eq!(chunk_starts(&[], gst::Fraction::new(25, 1), 50), vec![0]);
+    }
+}
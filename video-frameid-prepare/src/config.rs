@@ -0,0 +1,552 @@
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches};
+use gst;
+use num_cpus;
+
+pub use frameid_common::uri::{to_path, to_uri};
+
+use conform::{self, Conform, Fit};
+use encoding::{self, AudioCodec, EncodingSettings, Profile};
+use ladder::Rendition;
+use manifest;
+use package::{self, Packaging};
+use progress::{self, ProgressMode};
+use segments::SegmentContent;
+use stream::{self, StreamKind};
+use trim::{Numbering, Position, Trim};
+
+const POSITIONS: [&str; 4] = ["top-left", "top-right", "bottom-left", "bottom-right"];
+
+/// Length of the pre-roll and post-roll segments
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum SegmentLength {
+    Frames(u64),
+    Seconds(f64),
+}
+
+impl SegmentLength {
+    fn parse(value: &str) -> Result<SegmentLength, String> {
+        if value.ends_with('s') {
+            let seconds = value[..value.len() - 1].parse::<f64>()
+                .map_err(|_| format!("'{}' is not a number of seconds", value))?;
+            if seconds < 0.0 {
+                return Err(format!("'{}' is negative", value));
+            }
+            Ok(SegmentLength::Seconds(seconds))
+        } else {
+            value.parse::<u64>()
+                .map(SegmentLength::Frames)
+                .map_err(|_| format!("'{}' is not a number of frames (or seconds with an 's' suffix)", value))
+        }
+    }
+
+    /// Number of frames this length covers at the given framerate
+    pub fn frames(&self, framerate: gst::Fraction) -> u64 {
+        match *self {
+            SegmentLength::Frames(frames) => frames,
+            SegmentLength::Seconds(seconds) => {
+                (seconds * *framerate.numer() as f64 / *framerate.denom() as f64).ceil() as u64
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub input: String,
+    /// Further inputs played after `input`
+    pub append: Vec<String>,
+    pub output: String,
+    pub start_prefix: String,
+    pub content_prefix: String,
+    pub end_prefix: String,
+    pub position: String,
+    pub preroll: SegmentLength,
+    pub postroll: SegmentLength,
+    pub preroll_content: SegmentContent,
+    pub postroll_content: SegmentContent,
+    /// Range of the input that gets prepared
+    pub trim: Trim,
+    pub conform: Conform,
+    pub encoding: EncodingSettings,
+    /// audiotestsrc wave used for the pre-roll and post-roll audio
+    pub audio_filler: String,
+    pub manifest_format: String,
+    pub progress: ProgressMode,
+    /// Decode the output afterwards and check every id can be read back
+    pub verify: bool,
+    /// The input is a directory, glob or playlist and the output a directory
+    pub batch: bool,
+    /// Number of inputs prepared in parallel in batch mode, or of chunks
+    pub jobs: usize,
+    /// Length of the chunks the input is split in, in seconds
+    pub chunk_duration: Option<f64>,
+    /// Send the output live to the `output` destination instead of a file
+    pub stream: Option<StreamKind>,
+    /// Loopback port the HLS files are served on
+    pub hls_port: u16,
+    /// Renditions prepared into the output directory instead of a single
+    /// output
+    pub ladder: Vec<Rendition>,
+    pub packaging: Option<Packaging>,
+    /// Video sink the sequence is played to instead of being encoded
+    pub play: Option<String>,
+}
+
+fn validate_length(value: String) -> Result<(), String> {
+    SegmentLength::parse(&value).map(|_| ())
+}
+
+fn validate_position(value: String) -> Result<(), String> {
+    Position::parse(&value).map(|_| ())
+}
+
+fn validate_framerate(value: String) -> Result<(), String> {
+    Conform::parse_framerate(&value).map(|_| ())
+}
+
+fn validate_size(value: String) -> Result<(), String> {
+    Conform::parse_size(&value).map(|_| ())
+}
+
+fn validate_ladder(value: String) -> Result<(), String> {
+    Rendition::parse_ladder(&value).map(|_| ())
+}
+
+fn validate_content(value: String) -> Result<(), String> {
+    SegmentContent::parse(&value).map(|_| ())
+}
+
+fn validate_input(value: String) -> Result<(), String> {
+    // Glob patterns are only expanded in batch mode
+    let is_glob = value.contains(|c| c == '*' || c == '?' || c == '[');
+    if value.contains("://") || is_glob || Path::new(&value).exists() {
+        Ok(())
+    } else {
+        Err(format!("{} does not exist", value))
+    }
+}
+
+fn validate_seconds(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive number of seconds", value)),
+    }
+}
+
+fn validate_port(value: String) -> Result<(), String> {
+    value.parse::<u16>().map(|_| ()).map_err(|_| format!("'{}' is not a port number", value))
+}
+
+fn validate_u32(value: String) -> Result<(), String> {
+    value.parse::<u32>().map(|_| ()).map_err(|_| format!("'{}' is not a non-negative integer", value))
+}
+
+fn validate_positive(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("'{}' is not a positive integer", value)),
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("video-frameid-prepare")
+        .about("Tags every frame of a video with a qrcode id and adds identifiable start and end segments")
+        .arg(Arg::with_name("input")
+             .help("Input file path or URI, or in batch mode a directory, glob pattern or playlist file")
+             .required(true)
+             .validator(validate_input))
+        .arg(Arg::with_name("output")
+             .help("Output file path, in batch or ladder mode the output directory, with --stream the destination (a URI, or a directory for HLS), or with --play the path the logs are written next to")
+             .required(true))
+        .arg(Arg::with_name("append")
+             .long("append")
+             .value_name("INPUT")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(validate_input)
+             .conflicts_with("batch")
+             .help("Another input to play after the previous ones, converted to the first input's caps (can be repeated)"))
+        .arg(Arg::with_name("start")
+             .long("start")
+             .value_name("POSITION")
+             .validator(validate_position)
+             .conflicts_with("append")
+             .help("First frame of the input to prepare: a frame number, seconds (12.5s) or a timestamp ([HH:]MM:SS[.fff])"))
+        .arg(Arg::with_name("end")
+             .long("end")
+             .value_name("POSITION")
+             .validator(validate_position)
+             .conflicts_with("append")
+             .help("Position the prepared range stops before, in the same formats as --start"))
+        .arg(Arg::with_name("numbering")
+             .long("numbering")
+             .value_name("NUMBERING")
+             .possible_values(&["restart", "source"])
+             .default_value("restart")
+             .help("Whether the ids of a trimmed input restart at zero or keep the source frame numbers"))
+        .arg(Arg::with_name("framerate")
+             .long("framerate")
+             .value_name("FPS")
+             .validator(validate_framerate)
+             .help("Converts the inputs to this constant framerate (e.g. 30000/1001), needed for variable framerate inputs"))
+        .arg(Arg::with_name("size")
+             .long("size")
+             .value_name("WIDTHxHEIGHT")
+             .validator(validate_size)
+             .help("Scales the inputs to this resolution with square pixels"))
+        .arg(Arg::with_name("fit")
+             .long("fit")
+             .value_name("FIT")
+             .possible_values(&["letterbox", "crop", "stretch"])
+             .default_value("letterbox")
+             .help("How inputs with a different aspect ratio are fitted into the output"))
+        .arg(Arg::with_name("pixel-format")
+             .long("pixel-format")
+             .value_name("FORMAT")
+             .possible_values(&conform::FORMATS)
+             .help("Raw format the inputs are converted to and encoded from, by default I420 or I420_10LE depending on the input's bit depth"))
+        .arg(Arg::with_name("batch")
+             .long("batch")
+             .help("Prepare every input of a directory, glob pattern or playlist (.txt/.m3u) into the output directory"))
+        .arg(Arg::with_name("jobs")
+             .long("jobs")
+             .short("j")
+             .value_name("N")
+             .validator(validate_positive)
+             .help("Number of inputs prepared in parallel in batch mode, or of chunks with --chunk-duration [default: number of CPUs]"))
+        .arg(Arg::with_name("chunk-duration")
+             .long("chunk-duration")
+             .value_name("SECONDS")
+             .validator(validate_seconds)
+             .conflicts_with_all(&["append", "start", "end", "stream"])
+             .help("Split the input at keyframes into chunks of about this length, prepared in parallel and joined without re-encoding"))
+        .arg(Arg::with_name("stream")
+             .long("stream")
+             .value_name("KIND")
+             .possible_values(&stream::KINDS)
+             .conflicts_with_all(&["batch", "verify", "fragmented"])
+             .help("Stream the output live on this machine, paced in real time: rtp (udp://127.0.0.1:PORT), rtsp (rtsp://127.0.0.1:PORT/PATH), srt (srt://127.0.0.1:PORT) or hls (a directory)"))
+        .arg(Arg::with_name("hls-port")
+             .long("hls-port")
+             .value_name("PORT")
+             .default_value("8080")
+             .validator(validate_port)
+             .help("Port of the loopback HTTP server for --stream hls"))
+        .arg(Arg::with_name("ladder")
+             .long("ladder")
+             .value_name("RENDITIONS")
+             .validator(validate_ladder)
+             .conflicts_with_all(&["batch", "stream", "size", "bitrate", "quality"])
+             .help("Prepare a rendition per WIDTHxHEIGHT@KBPS of this comma separated list into the output directory, each with its rendition id (r0, r1...) after the prefixes"))
+        .arg(Arg::with_name("package")
+             .long("package")
+             .value_name("FORMAT")
+             .possible_values(&package::PACKAGINGS)
+             .requires("ladder")
+             .help("Also package the renditions as HLS or DASH in the output directory"))
+        .arg(Arg::with_name("play")
+             .long("play")
+             .conflicts_with_all(&["batch", "stream", "ladder", "chunk-duration", "verify", "fragmented"])
+             .help("Play the tagged sequence to a video sink instead of encoding it, and log when each frame is rendered next to the output path"))
+        .arg(Arg::with_name("video-sink")
+             .long("video-sink")
+             .value_name("DESCRIPTION")
+             .default_value("autovideosink")
+             .help("Video sink used with --play, with properties (e.g. fakesink for headless runs)"))
+        .arg(Arg::with_name("start-prefix")
+             .long("start-prefix")
+             .value_name("PREFIX")
+             .default_value("s:")
+             .help("Id prefix of the pre-roll frames"))
+        .arg(Arg::with_name("content-prefix")
+             .long("content-prefix")
+             .value_name("PREFIX")
+             .default_value("f:")
+             .help("Id prefix of the input frames"))
+        .arg(Arg::with_name("end-prefix")
+             .long("end-prefix")
+             .value_name("PREFIX")
+             .default_value("e:")
+             .help("Id prefix of the post-roll frames"))
+        .arg(Arg::with_name("position")
+             .long("position")
+             .value_name("POSITION")
+             .possible_values(&POSITIONS)
+             .default_value("bottom-right")
+             .help("Corner the qrcode is drawn at"))
+        .arg(Arg::with_name("preroll")
+             .long("preroll")
+             .value_name("LENGTH")
+             .default_value("150")
+             .validator(validate_length)
+             .help("Length of the start segment in frames, or in seconds with an 's' suffix (e.g. 5s)"))
+        .arg(Arg::with_name("postroll")
+             .long("postroll")
+             .value_name("LENGTH")
+             .default_value("150")
+             .validator(validate_length)
+             .help("Length of the end segment in frames, or in seconds with an 's' suffix (e.g. 5s)"))
+        .arg(Arg::with_name("preroll-content")
+             .long("preroll-content")
+             .value_name("CONTENT")
+             .default_value("bars")
+             .validator(validate_content)
+             .help("Content of the start segment: black, grey, bars, countdown, image:<PATH|URI> or clip:<PATH|URI>"))
+        .arg(Arg::with_name("postroll-content")
+             .long("postroll-content")
+             .value_name("CONTENT")
+             .default_value("bars")
+             .validator(validate_content)
+             .help("Content of the end segment: black, grey, bars, countdown, image:<PATH|URI> or clip:<PATH|URI>"))
+        .arg(Arg::with_name("profile")
+             .long("profile")
+             .value_name("PROFILE")
+             .possible_values(&encoding::PROFILES)
+             .default_value("h264")
+             .help("Encoding profile of the output"))
+        .arg(Arg::with_name("bitrate")
+             .long("bitrate")
+             .value_name("KBPS")
+             .validator(validate_positive)
+             .conflicts_with("quality")
+             .help("Target bitrate in kbit/s"))
+        .arg(Arg::with_name("quality")
+             .long("quality")
+             .value_name("LEVEL")
+             .validator(validate_u32)
+             .help("Constant quality level (x264 quantizer, x265 qp, VP9/AV1 cq-level) [default without --bitrate: 23 for x264, 28 for x265, 31 for VP9, 30 for AV1]"))
+        .arg(Arg::with_name("keyframe-interval")
+             .long("keyframe-interval")
+             .value_name("FRAMES")
+             .validator(validate_positive)
+             .help("Maximum number of frames between keyframes [default: 250 for x264 and x265, 240 for VP9 and AV1]"))
+        .arg(Arg::with_name("encoder")
+             .long("encoder")
+             .value_name("DESCRIPTION")
+             .required_if("profile", "custom")
+             .conflicts_with_all(&["bitrate", "quality", "keyframe-interval", "ladder"])
+             .help("Encoder element with properties (e.g. \"x264enc tune=zerolatency\"), replaces the profile's encoder and its rate control, so set the bitrate, quality and keyframe interval in the description"))
+        .arg(Arg::with_name("muxer")
+             .long("muxer")
+             .value_name("ELEMENT")
+             .help("Container muxer element, replaces the profile's container"))
+        .arg(Arg::with_name("fragmented")
+             .long("fragmented")
+             .help("Write fragmented MP4 or streamable Matroska/WebM, which stays playable if the process is killed"))
+        .arg(Arg::with_name("fragment-duration")
+             .long("fragment-duration")
+             .value_name("MS")
+             .default_value("1000")
+             .validator(validate_positive)
+             .help("Length of the MP4 fragments with --fragmented, in milliseconds"))
+        .arg(Arg::with_name("audio-codec")
+             .long("audio-codec")
+             .value_name("CODEC")
+             .possible_values(&encoding::AUDIO_CODECS)
+             .default_value("auto")
+             .help("Codec the input audio is re-encoded to, 'auto' picks one that fits the container"))
+        .arg(Arg::with_name("audio-filler")
+             .long("audio-filler")
+             .value_name("FILLER")
+             .possible_values(&["silence", "tone"])
+             .default_value("silence")
+             .help("Audio of the start and end segments"))
+        .arg(Arg::with_name("manifest")
+             .long("manifest")
+             .value_name("FORMAT")
+             .possible_values(&manifest::FORMATS)
+             .default_value("json")
+             .help("Format of the per-frame manifest written next to the output"))
+        .arg(Arg::with_name("progress")
+             .long("progress")
+             .value_name("MODE")
+             .possible_values(&progress::MODES)
+             .default_value("human")
+             .help("Progress reported on stderr every second: human readable, one JSON object per line, or none"))
+        .arg(Arg::with_name("verify")
+             .long("verify")
+             .help("Decode the output afterwards and fail unless every frame id reads back complete and in order"))
+}
+
+impl Config {
+    /// Path the manifest is written next to, with its own extension
+    pub fn manifest_base(&self) -> String {
+        match self.stream {
+            None => self.output.clone(),
+            Some(StreamKind::Hls) => Path::new(&self.output).join("playlist").to_string_lossy().into_owned(),
+            // Network destinations aren't paths, so the manifest goes to the
+            // working directory
+            Some(kind) => format!("{}-stream", kind.name()),
+        }
+    }
+
+    /// All inputs in the order they are played
+    pub fn inputs(&self) -> Vec<&str> {
+        let mut inputs = vec![self.input.as_ref()];
+        inputs.extend(self.append.iter().map(String::as_ref));
+        inputs
+    }
+
+    pub fn from_args() -> Result<Config, String> {
+        Config::from_matches(&app().get_matches())
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Config, String> {
+        // Validators already ran, so only conversion errors are left
+        let value = |name| matches.value_of(name).unwrap().to_owned();
+        let optional = |name| matches.value_of(name).map(str::to_owned);
+        let number = |name| matches.value_of(name).map(|v| v.parse::<u32>().unwrap());
+
+        let batch = matches.is_present("batch");
+        let stream = matches.value_of("stream").map(|kind| StreamKind::from_name(kind).unwrap());
+        let packaging = matches.value_of("package").map(|packaging| Packaging::from_name(packaging).unwrap());
+        let profile = Profile::from_name(&value("profile")).unwrap();
+        if let Some(packaging) = packaging {
+            packaging.check_profile(profile)?;
+        }
+        let output = match stream {
+            Some(kind) if kind != StreamKind::Hls => {
+                kind.check_destination(&value("output"))?;
+                value("output")
+            }
+            _ => to_path(&value("output"))?,
+        };
+
+        let trim = Trim {
+            start: matches.value_of("start").map(Position::parse).map_or(Ok(None), |p| p.map(Some))?,
+            end: matches.value_of("end").map(Position::parse).map_or(Ok(None), |p| p.map(Some))?,
+            numbering: match matches.value_of("numbering") {
+                Some("source") => Numbering::Source,
+                _ => Numbering::Restart,
+            },
+        };
+        trim.check()?;
+
+        let ladder = matches.value_of("ladder").map(Rendition::parse_ladder).unwrap_or_else(|| Ok(Vec::new()))?;
+        if !ladder.is_empty() {
+            Rendition::check_profile(profile)?;
+        }
+        let encoding = EncodingSettings {
+            profile,
+            bitrate: number("bitrate"),
+            quality: number("quality"),
+            keyframe_interval: number("keyframe-interval"),
+            encoder: optional("encoder"),
+            muxer: optional("muxer"),
+            audio_codec: AudioCodec::from_name(&value("audio-codec")).unwrap(),
+            fragment_duration: if matches.is_present("fragmented") { number("fragment-duration") } else { None },
+            live: stream.is_some(),
+            fixed_gop: false,
+        };
+        // The ladder picks the bitrate and GOP of each rendition itself
+        let encoding = if ladder.is_empty() { encoding.with_defaults() } else { encoding };
+
+        Ok(Config {
+            // Batch inputs are expanded and converted later
+            input: if batch { value("input") } else { to_uri(&value("input"))? },
+            append: matches.values_of("append")
+                .map(|values| values.map(to_uri).collect::<Result<Vec<_>, _>>())
+                .unwrap_or_else(|| Ok(Vec::new()))?,
+            output,
+            start_prefix: value("start-prefix"),
+            content_prefix: value("content-prefix"),
+            end_prefix: value("end-prefix"),
+            position: value("position"),
+            preroll: SegmentLength::parse(&value("preroll"))?,
+            postroll: SegmentLength::parse(&value("postroll"))?,
+            preroll_content: SegmentContent::parse(&value("preroll-content"))?,
+            postroll_content: SegmentContent::parse(&value("postroll-content"))?,
+            trim,
+            conform: Conform {
+                framerate: matches.value_of("framerate").map(Conform::parse_framerate).map_or(Ok(None), |f| f.map(Some))?,
+                size: matches.value_of("size").map(Conform::parse_size).map_or(Ok(None), |s| s.map(Some))?,
+                fit: match matches.value_of("fit") {
+                    Some("crop") => Fit::Crop,
+                    Some("stretch") => Fit::Stretch,
+                    _ => Fit::Letterbox,
+                },
+                format: matches.value_of("pixel-format")
+                    .and_then(|format| conform::FORMATS.iter().find(|&&f| f == format).cloned()),
+            },
+            encoding,
+            audio_filler: match matches.value_of("audio-filler") {
+                Some("tone") => "sine".to_owned(),
+                _ => "silence".to_owned(),
+            },
+            manifest_format: value("manifest"),
+            progress: ProgressMode::from_name(&value("progress")).unwrap(),
+            verify: matches.is_present("verify"),
+            batch,
+            jobs: number("jobs").map(|jobs| jobs as usize).unwrap_or_else(num_cpus::get),
+            chunk_duration: matches.value_of("chunk-duration").map(|seconds| seconds.parse().unwrap()),
+            stream,
+            hls_port: value("hls-port").parse().unwrap(),
+            ladder,
+            packaging,
+            play: if matches.is_present("play") { Some(value("video-sink")) } else { None },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(value: &str) -> u64 {
+        SegmentLength::parse(value).unwrap().frames(gst::Fraction::new(25, 1))
+    }
+
+    #[test]
+    fn segment_length_in_frames() {
+        assert_eq!(frames("30"), 30);
+        assert_eq!(frames("0"), 0);
+    }
+
+    #[test]
+    fn segment_length_in_seconds_covers_partial_frames() {
+        assert_eq!(frames("2s"), 50);
+        assert_eq!(frames("1.5s"), 38);
+        assert_eq!(frames("0s"), 0);
+    }
+
+    #[test]
+    fn segment_length_errors_name_the_unit() {
+        assert_eq!(SegmentLength::parse("-1s").unwrap_err(), "'-1s' is negative");
+        assert_eq!(SegmentLength::parse("twos").unwrap_err(), "'twos' is not a number of seconds");
+        // Fractional frames are read as a missing 's'
+        assert_eq!(SegmentLength::parse("1.5").unwrap_err(),
+                   "'1.5' is not a number of frames (or seconds with an 's' suffix)");
+    }
+
+    #[test]
+    fn counts_reject_zero_but_quality_accepts_it() {
+        assert_eq!(validate_positive("1".to_owned()), Ok(()));
+        assert_eq!(validate_positive("0".to_owned()), Err("'0' is not a positive integer".to_owned()));
+        assert_eq!(validate_u32("0".to_owned()), Ok(()));
+        assert_eq!(validate_u32("-1".to_owned()), Err("'-1' is not a non-negative integer".to_owned()));
+    }
+
+    fn matches(args: &[&str]) -> Result<ArgMatches<'static>, ::clap::Error> {
+        let mut all = vec!["video-frameid-prepare", "file:///input.mp4", "output"];
+        all.extend_from_slice(args);
+        app().get_matches_from_safe(all)
+    }
+
+    #[test]
+    fn encoder_replaces_the_rate_control_options() {
+        let conflict = |option| matches(&["--encoder=x264enc", option]).err().map(|e| e.kind);
+        assert_eq!(conflict("--bitrate=1000"), Some(::clap::ErrorKind::ArgumentConflict));
+        assert_eq!(conflict("--keyframe-interval=50"), Some(::clap::ErrorKind::ArgumentConflict));
+        assert_eq!(conflict("--muxer=matroskamux"), None);
+    }
+
+    #[test]
+    fn ladder_needs_the_h264_profile() {
+        let ladder = matches(&["--ladder=1280x720@2500", "--profile=vp9"]).unwrap();
+        assert_eq!(Config::from_matches(&ladder).err().unwrap(), "A ladder needs the h264 profile, not vp9");
+        let ladder = matches(&["--ladder=1280x720@2500", "--profile=h264"]).unwrap();
+        assert!(Config::from_matches(&ladder).is_ok());
+    }
+}
@@ -0,0 +1,108 @@
+use gst;
+
+use failure::Error;
+
+use discover::VideoGeometry;
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} has a variable framerate, pick one with --framerate", _0)]
+struct VariableFramerate(String);
+
+/// Raw formats the output can be prepared in
+pub const FORMATS: [&str; 3] = ["I420", "I420_10LE", "P010_10LE"];
+
+/// How inputs with a different aspect ratio are fitted into the target size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to fit and add black borders
+    Letterbox,
+    /// Scale to fill and crop what sticks out
+    Crop,
+    /// Scale to the target size, distorting the picture
+    Stretch,
+}
+
+/// Target caps every input is converted to, instead of the first input's
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Conform {
+    pub framerate: Option<(i32, i32)>,
+    pub size: Option<(i32, i32)>,
+    pub fit: Fit,
+    /// One of FORMATS, instead of the one matching the input's bit depth
+    pub format: Option<&'static str>,
+}
+
+impl Conform {
+    pub fn parse_framerate(value: &str) -> Result<(i32, i32), String> {
+        let invalid = || format!("'{}' is not a framerate like 30, 25/1 or 30000/1001", value);
+        let mut parts = value.splitn(2, '/');
+        let numer = parts.next().unwrap().parse::<i32>().map_err(|_| invalid())?;
+        let denom = parts.next().map_or(Ok(1), |d| d.parse::<i32>()).map_err(|_| invalid())?;
+
+        if numer <= 0 || denom <= 0 {
+            return Err(invalid());
+        }
+        Ok((numer, denom))
+    }
+
+    pub fn parse_size(value: &str) -> Result<(i32, i32), String> {
+        let invalid = || format!("'{}' is not a size like 1280x720", value);
+        let mut parts = value.splitn(2, 'x');
+        let width = parts.next().unwrap().parse::<i32>().map_err(|_| invalid())?;
+        let height = parts.next().ok_or_else(invalid)?.parse::<i32>().map_err(|_| invalid())?;
+
+        // I420 needs even dimensions
+        if width <= 0 || height <= 0 || width % 2 != 0 || height % 2 != 0 {
+            return Err(invalid());
+        }
+        Ok((width, height))
+    }
+
+    /// Caps the first input is converted to
+    pub fn target(&self, uri: &str, source: &VideoGeometry) -> Result<VideoGeometry, Error> {
+        let mut target = source.clone();
+
+        if let Some((numer, denom)) = self.framerate {
+            target.framerate = gst::Fraction::new(numer, denom);
+        } else if source.is_variable_framerate() {
+            Err(VariableFramerate(uri.to_owned()))?;
+        }
+
+        if let Some((width, height)) = self.size {
+            target.width = width;
+            target.height = height;
+            target.pixel_aspect_ratio = gst::Fraction::new(1, 1);
+        }
+
+        if let Some(format) = self.format {
+            target.format = format.to_owned();
+        }
+
+        Ok(target)
+    }
+
+    /// Pixels to remove from the (left, right, top, bottom) of `source` so it
+    /// fills `target` without borders
+    pub fn crop(&self, source: &VideoGeometry, target: &VideoGeometry) -> (i32, i32, i32, i32) {
+        if self.fit != Fit::Crop {
+            return (0, 0, 0, 0);
+        }
+
+        let dar = |g: &VideoGeometry| {
+            g.width as f64 * *g.pixel_aspect_ratio.numer() as f64
+                / (g.height as f64 * *g.pixel_aspect_ratio.denom() as f64)
+        };
+        let (source_dar, target_dar) = (dar(source), dar(target));
+
+        if source_dar > target_dar {
+            let width = (source.width as f64 * target_dar / source_dar).round() as i32;
+            let left = (source.width - width) / 2;
+            (left, source.width - width - left, 0, 0)
+        } else {
+            let height = (source.height as f64 * source_dar / target_dar).round() as i32;
+            let top = (source.height - height) / 2;
+            (0, 0, top, source.height - height - top)
+        }
+    }
+}
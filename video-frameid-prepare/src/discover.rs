@@ -0,0 +1,247 @@
+use gst;
+use gst_pbutils;
+use gst_video;
+use gst_pbutils::prelude::*;
+
+use failure::Error;
+
+// How long the discoverer may take to preroll the input before giving up
+const DISCOVER_TIMEOUT_SECONDS: u64 = 15;
+
+#[derive(Debug, Fail)]
+#[fail(display = "No video stream found in {}", _0)]
+struct NoVideoStream(String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} has no frames", _0)]
+pub struct EmptyInput(pub String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Missing {} in video caps of {}", field, uri)]
+struct MissingCapsField {
+    field: &'static str,
+    uri: String,
+}
+
+/// Raw video properties of the input that the pre-roll and post-roll
+/// segments need to reproduce so that concat never renegotiates.
+#[derive(Debug, Clone)]
+pub struct VideoGeometry {
+    pub width: i32,
+    pub height: i32,
+    pub framerate: gst::Fraction,
+    pub pixel_aspect_ratio: gst::Fraction,
+    /// Raw format everything is converted to: I420, or I420_10LE for
+    /// inputs of more than 8 bits
+    pub format: String,
+    pub colorimetry: Option<String>,
+    /// HDR10 static metadata, in their caps serialisation
+    pub mastering_display_info: Option<String>,
+    pub content_light_level: Option<String>,
+}
+
+impl VideoGeometry {
+    /// Variable framerate inputs report 0/1
+    pub fn is_variable_framerate(&self) -> bool {
+        *self.framerate.numer() == 0
+    }
+
+    /// Raw caps with the input's size, framerate and format, which is what
+    /// the frame id is drawn in
+    pub fn scaled_caps(&self) -> gst::Caps {
+        gst::Caps::new_simple(
+            "video/x-raw",
+            &[
+                ("format", &self.format),
+                ("width", &self.width),
+                ("height", &self.height),
+                ("framerate", &self.framerate),
+                ("pixel-aspect-ratio", &self.pixel_aspect_ratio),
+            ],
+        )
+    }
+
+    /// Raw caps matching the input, with its colorimetry and HDR metadata so
+    /// the encoder and muxer signal them
+    pub fn caps(&self) -> gst::Caps {
+        let mut caps = gst::Caps::new_simple(
+            "video/x-raw",
+            &[
+                ("format", &self.format),
+                ("width", &self.width),
+                ("height", &self.height),
+                ("framerate", &self.framerate),
+                ("pixel-aspect-ratio", &self.pixel_aspect_ratio),
+            ],
+        );
+
+        {
+            let caps = caps.get_mut().unwrap();
+            if let Some(ref colorimetry) = self.colorimetry {
+                caps.set_simple(&[("colorimetry", colorimetry)]);
+            }
+            if let Some(ref info) = self.mastering_display_info {
+                caps.set_simple(&[("mastering-display-info", info)]);
+            }
+            if let Some(ref level) = self.content_light_level {
+                caps.set_simple(&[("content-light-level", level)]);
+            }
+        }
+
+        caps
+    }
+}
+
+/// Raw audio properties of the input, used for the silence or tone that
+/// pads the pre-roll and post-roll segments.
+#[derive(Debug, Clone)]
+pub struct AudioFormat {
+    pub rate: i32,
+    pub channels: i32,
+}
+
+impl AudioFormat {
+    pub fn caps(&self) -> gst::Caps {
+        gst::Caps::new_simple(
+            "audio/x-raw",
+            &[
+                ("format", &"S16LE"),
+                ("layout", &"interleaved"),
+                ("rate", &self.rate),
+                ("channels", &self.channels),
+            ],
+        )
+    }
+
+    /// Size in bytes of one sample of every channel, in the format of `caps`
+    pub fn bytes_per_sample(&self) -> u64 {
+        2 * self.channels as u64
+    }
+
+    /// Number of samples covering exactly `frames` video frames
+    pub fn samples_for(&self, frames: u64, framerate: gst::Fraction) -> u64 {
+        (frames * self.rate as u64 * *framerate.denom() as u64 + *framerate.numer() as u64 / 2)
+            / *framerate.numer() as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    pub video: VideoGeometry,
+    pub audio: Option<AudioFormat>,
+    pub duration: gst::ClockTime,
+}
+
+impl MediaInfo {
+    /// Number of video frames the input lasts at `framerate`, as far as the
+    /// container knows
+    pub fn frames(&self, framerate: gst::Fraction) -> u64 {
+        let duration = self.duration.nseconds().unwrap_or(0);
+
+        (duration as f64 * *framerate.numer() as f64
+            / (*framerate.denom() as f64 * 1_000_000_000f64)).round() as u64
+    }
+}
+
+pub fn discover(uri: &str) -> Result<MediaInfo, Error> {
+    let discoverer = gst_pbutils::Discoverer::new(gst::ClockTime::from_seconds(DISCOVER_TIMEOUT_SECONDS))?;
+    let info = discoverer.discover_uri(uri)?;
+
+    Ok(MediaInfo {
+        video: video_geometry(uri, &info)?,
+        audio: audio_format(&info),
+        duration: info.get_duration(),
+    })
+}
+
+fn audio_format(info: &gst_pbutils::DiscovererInfo) -> Option<AudioFormat> {
+    let caps = info.get_audio_streams()
+        .iter()
+        .filter_map(|stream| stream.get_caps())
+        .next()?;
+    let s = caps.get_structure(0)?;
+
+    // Not every encoded format reports these, fall back to the common case
+    Some(AudioFormat {
+        rate: s.get::<i32>("rate").unwrap_or(48000),
+        channels: s.get::<i32>("channels").unwrap_or(2),
+    })
+}
+
+/// Bits per sample of the video, from whatever the caps say about it. Parsers
+/// report the depth, raw video has it in its format, otherwise it's only in
+/// the profile.
+fn bit_depth(s: &gst::StructureRef) -> u32 {
+    if let Some(depth) = s.get::<u32>("bit-depth-luma") {
+        return depth;
+    }
+
+    if let Some(format) = s.get::<String>("format") {
+        let format = gst_video::VideoFormat::from_string(&format);
+        if format != gst_video::VideoFormat::Unknown {
+            return gst_video::VideoFormatInfo::from_format(format).depth()[0];
+        }
+    }
+
+    let profile = s.get::<String>("profile").unwrap_or_default();
+    if profile.contains("10") || profile.contains("12") || (s.get_name() == "video/x-vp9" && (profile == "2" || profile == "3")) {
+        10
+    } else {
+        8
+    }
+}
+
+fn video_geometry(uri: &str, info: &gst_pbutils::DiscovererInfo) -> Result<VideoGeometry, Error> {
+    let caps = info.get_video_streams()
+        .iter()
+        .filter_map(|stream| stream.get_caps())
+        .next()
+        .ok_or_else(|| NoVideoStream(uri.to_owned()))?;
+    let s = caps.get_structure(0).ok_or_else(|| NoVideoStream(uri.to_owned()))?;
+
+    let missing = |field| MissingCapsField { field, uri: uri.to_owned() };
+
+    let width = s.get::<i32>("width").ok_or_else(|| missing("width"))?;
+    let height = s.get::<i32>("height").ok_or_else(|| missing("height"))?;
+    // Variable framerate inputs may not report one at all
+    let framerate = s.get::<gst::Fraction>("framerate")
+        .unwrap_or_else(|| gst::Fraction::new(0, 1));
+    let pixel_aspect_ratio = s.get::<gst::Fraction>("pixel-aspect-ratio")
+        .unwrap_or_else(|| gst::Fraction::new(1, 1));
+    let format = if bit_depth(s) > 8 { "I420_10LE" } else { "I420" };
+
+    Ok(VideoGeometry {
+        width,
+        height,
+        framerate,
+        pixel_aspect_ratio,
+        format: format.to_owned(),
+        colorimetry: s.get::<String>("colorimetry"),
+        mastering_display_info: s.get::<String>("mastering-display-info"),
+        content_light_level: s.get::<String>("content-light-level"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_depth_of_raw_video_comes_from_its_format() {
+        gst::init().unwrap();
+        let raw = |format: &str| gst::Structure::new("video/x-raw", &[("format", &format)]);
+        assert_eq!(bit_depth(&raw("NV12")), 8);
+        assert_eq!(bit_depth(&raw("I420_12LE")), 12);
+        assert_eq!(bit_depth(&raw("P010_10LE")), 10);
+    }
+
+    #[test]
+    fn bit_depth_of_encoded_video_comes_from_its_profile() {
+        gst::init().unwrap();
+        let h264 = |profile: &str| gst::Structure::new("video/x-h264", &[("profile", &profile)]);
+        assert_eq!(bit_depth(&h264("high-10")), 10);
+        assert_eq!(bit_depth(&h264("high")), 8);
+        let vp9 = gst::Structure::new("video/x-vp9", &[("profile", &"2")]);
+        assert_eq!(bit_depth(&vp9), 10);
+    }
+}
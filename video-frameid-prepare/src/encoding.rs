@@ -0,0 +1,323 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use make_element;
+
+pub const PROFILES: [&str; 8] = ["h264", "h264-lossless", "h265", "ffv1", "vp9", "av1", "y4m", "custom"];
+pub const AUDIO_CODECS: [&str; 6] = ["auto", "aac", "opus", "vorbis", "flac", "none"];
+
+#[derive(Debug, Fail)]
+#[fail(display = "Profile {} does not support {}", profile, setting)]
+struct UnsupportedSetting {
+    profile: &'static str,
+    setting: &'static str,
+}
+
+/// MP4 muxers write a fragment at a time instead of a moov atom at the
+/// end. Matroska muxers don't seek back to write the header, so a
+/// truncated file looks like a live stream that hasn't ended.
+fn make_fragmented(muxer: &gst::Element, duration: u32) -> Result<(), Error> {
+    if muxer.find_property("fragment-duration").is_some() {
+        muxer.set_property("fragment-duration", &duration)?;
+    } else if muxer.find_property("streamable").is_some() {
+        muxer.set_property("streamable", &true)?;
+    } else {
+        Err(format_err!("{} can't write fragmented output",
+                        muxer.get_factory().map(|f| f.get_name()).unwrap_or_default()))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// x264 in MP4, rate controlled by bitrate or quantizer
+    H264,
+    /// x264 with qp=0, for visually (and numerically) lossless references
+    H264Lossless,
+    /// x265 in MP4, which takes 10-bit input for HDR
+    H265,
+    /// FFV1 in Matroska
+    Ffv1,
+    /// VP9 in WebM
+    Vp9,
+    /// AV1 in WebM
+    Av1,
+    /// Uncompressed YUV4MPEG2
+    Y4m,
+    /// Encoder and muxer given on the command line
+    Custom,
+}
+
+impl Profile {
+    pub fn from_name(name: &str) -> Option<Profile> {
+        match name {
+            "h264" => Some(Profile::H264),
+            "h264-lossless" => Some(Profile::H264Lossless),
+            "h265" => Some(Profile::H265),
+            "ffv1" => Some(Profile::Ffv1),
+            "vp9" => Some(Profile::Vp9),
+            "av1" => Some(Profile::Av1),
+            "y4m" => Some(Profile::Y4m),
+            "custom" => Some(Profile::Custom),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Profile::H264 => "h264",
+            Profile::H264Lossless => "h264-lossless",
+            Profile::H265 => "h265",
+            Profile::Ffv1 => "ffv1",
+            Profile::Vp9 => "vp9",
+            Profile::Av1 => "av1",
+            Profile::Y4m => "y4m",
+            Profile::Custom => "custom",
+        }
+    }
+
+    /// Quality level used when neither a bitrate nor a quality is given,
+    /// None for profiles without rate control
+    pub fn default_quality(&self) -> Option<u32> {
+        match *self {
+            Profile::H264 => Some(23),
+            Profile::H265 => Some(28),
+            Profile::Vp9 => Some(31),
+            Profile::Av1 => Some(30),
+            Profile::H264Lossless | Profile::Ffv1 | Profile::Y4m | Profile::Custom => None,
+        }
+    }
+
+    /// Keyframe interval used when none is given, None for profiles whose
+    /// frames are all keyframes
+    pub fn default_keyframe_interval(&self) -> Option<u32> {
+        match *self {
+            Profile::H264 | Profile::H264Lossless | Profile::H265 => Some(250),
+            Profile::Vp9 | Profile::Av1 => Some(240),
+            Profile::Ffv1 | Profile::Y4m | Profile::Custom => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    /// Whatever fits the container best
+    Auto,
+    Aac,
+    Opus,
+    Vorbis,
+    Flac,
+    /// Drop the audio
+    None,
+}
+
+impl AudioCodec {
+    pub fn from_name(name: &str) -> Option<AudioCodec> {
+        match name {
+            "auto" => Some(AudioCodec::Auto),
+            "aac" => Some(AudioCodec::Aac),
+            "opus" => Some(AudioCodec::Opus),
+            "vorbis" => Some(AudioCodec::Vorbis),
+            "flac" => Some(AudioCodec::Flac),
+            "none" => Some(AudioCodec::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EncodingSettings {
+    pub profile: Profile,
+    /// Target bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    /// Constant quality level, in the encoder's own quantizer scale
+    pub quality: Option<u32>,
+    /// Maximum distance between keyframes, in frames
+    pub keyframe_interval: Option<u32>,
+    /// Encoder description overriding the profile's encoder
+    pub encoder: Option<String>,
+    /// Muxer element overriding the profile's container
+    pub muxer: Option<String>,
+    pub audio_codec: AudioCodec,
+    /// Fragment length in milliseconds, for output that stays playable if
+    /// the process dies before finalising it
+    pub fragment_duration: Option<u32>,
+    /// Encoding for a live stream, which needs low latency
+    pub live: bool,
+    /// Keyframes only every `keyframe_interval` frames, so they line up
+    /// between renditions of a ladder
+    pub fixed_gop: bool,
+}
+
+impl EncodingSettings {
+    /// Fills in the profile's rate control and keyframe interval where
+    /// none was given, so the settings say what the encoder was told
+    /// instead of leaving it to the encoder's own defaults
+    pub fn with_defaults(mut self) -> EncodingSettings {
+        if self.encoder.is_some() {
+            return self;
+        }
+        if self.bitrate.is_none() && self.quality.is_none() {
+            self.quality = self.profile.default_quality();
+        }
+        if self.keyframe_interval.is_none() {
+            self.keyframe_interval = self.profile.default_keyframe_interval();
+        }
+        self
+    }
+
+    /// gst-launch style description of the configured encoder
+    fn encoder_description(&self) -> Result<String, Error> {
+        if let Some(ref encoder) = self.encoder {
+            return Ok(encoder.clone());
+        }
+
+        let unsupported = |setting| UnsupportedSetting { profile: self.profile.name(), setting };
+
+        let mut description = match self.profile {
+            Profile::H264 => match (self.bitrate, self.quality) {
+                (Some(_), Some(_)) => Err(unsupported("both bitrate and quality"))?,
+                (Some(bitrate), None) => format!("x264enc pass=cbr bitrate={}", bitrate),
+                (None, Some(quality)) => format!("x264enc pass=quant quantizer={}", quality),
+                (None, None) => "x264enc".to_owned(),
+            },
+            Profile::H264Lossless => {
+                if self.bitrate.is_some() || self.quality.is_some() {
+                    Err(unsupported("rate control"))?;
+                }
+                "x264enc pass=quant quantizer=0 speed-preset=ultrafast".to_owned()
+            }
+            Profile::H265 => match (self.bitrate, self.quality) {
+                (Some(_), Some(_)) => Err(unsupported("both bitrate and quality"))?,
+                (Some(bitrate), None) => format!("x265enc bitrate={}", bitrate),
+                (None, Some(quality)) => format!("x265enc qp={}", quality),
+                (None, None) => "x265enc".to_owned(),
+            },
+            Profile::Ffv1 => {
+                if self.bitrate.is_some() || self.quality.is_some() {
+                    Err(unsupported("rate control"))?;
+                }
+                "avenc_ffv1".to_owned()
+            }
+            Profile::Vp9 => match (self.bitrate, self.quality) {
+                (Some(_), Some(_)) => Err(unsupported("both bitrate and quality"))?,
+                (Some(bitrate), None) => format!("vp9enc end-usage=cbr target-bitrate={} deadline=1", bitrate * 1000),
+                (None, Some(quality)) => format!("vp9enc end-usage=cq cq-level={} deadline=1", quality),
+                (None, None) => "vp9enc deadline=1".to_owned(),
+            },
+            Profile::Av1 => match (self.bitrate, self.quality) {
+                (Some(_), Some(_)) => Err(unsupported("both bitrate and quality"))?,
+                (Some(bitrate), None) => format!("av1enc end-usage=cbr target-bitrate={}", bitrate),
+                (None, Some(quality)) => format!("av1enc end-usage=q cq-level={}", quality),
+                (None, None) => "av1enc".to_owned(),
+            },
+            Profile::Y4m => {
+                if self.bitrate.is_some() || self.quality.is_some() || self.keyframe_interval.is_some() {
+                    Err(unsupported("rate control or keyframes"))?;
+                }
+                "y4menc".to_owned()
+            }
+            Profile::Custom => unreachable!("custom profile without an encoder"),
+        };
+
+        if let Some(interval) = self.keyframe_interval {
+            let property = match self.profile {
+                Profile::H264 | Profile::H264Lossless | Profile::H265 => "key-int-max",
+                Profile::Ffv1 => "gop-size",
+                Profile::Vp9 | Profile::Av1 => "keyframe-max-dist",
+                Profile::Y4m | Profile::Custom => unreachable!(),
+            };
+            description.push_str(&format!(" {}={}", property, interval));
+        }
+
+        let x26x = match self.profile {
+            Profile::H264 | Profile::H264Lossless | Profile::H265 => true,
+            _ => false,
+        };
+
+        if self.fixed_gop && x26x {
+            description.push_str(" option-string=\"scenecut=0\"");
+        }
+
+        // x264 and x265 would otherwise hold back frames for lookahead and B-frames
+        if self.live && x26x {
+            description.push_str(" tune=zerolatency");
+        }
+
+        Ok(description)
+    }
+
+    /// Creates the configured encoder. Descriptions are parsed so enum
+    /// properties can be given by their nick.
+    pub fn make_encoder(&self) -> Result<gst::Element, Error> {
+        Ok(gst::parse_launch(&self.encoder_description()?)?)
+    }
+
+    /// Creates the container muxer, if the profile has one
+    pub fn make_muxer(&self) -> Result<Option<gst::Element>, Error> {
+        let muxer = match (self.muxer.as_ref(), self.profile) {
+            (Some(muxer), _) => muxer.as_str(),
+            (None, Profile::H264) | (None, Profile::H264Lossless) | (None, Profile::H265) | (None, Profile::Custom) => "mp4mux",
+            (None, Profile::Ffv1) => "matroskamux",
+            (None, Profile::Vp9) | (None, Profile::Av1) => "webmmux",
+            (None, Profile::Y4m) => return Ok(None),
+        };
+        let muxer = make_element(muxer)?;
+
+        if let Some(duration) = self.fragment_duration {
+            make_fragmented(&muxer, duration)?;
+        }
+
+        Ok(Some(muxer))
+    }
+
+    /// Creates the audio encoder, or None if the output carries no audio
+    pub fn make_audio_encoder(&self) -> Result<Option<gst::Element>, Error> {
+        let codec = match self.audio_codec {
+            AudioCodec::Auto => match self.extension() {
+                "mp4" | "ts" => AudioCodec::Aac,
+                "webm" => AudioCodec::Opus,
+                "mkv" => AudioCodec::Flac,
+                _ => AudioCodec::None,
+            },
+            codec => codec,
+        };
+
+        let encoder = match codec {
+            AudioCodec::Aac => "avenc_aac",
+            AudioCodec::Opus => "opusenc",
+            AudioCodec::Vorbis => "vorbisenc",
+            AudioCodec::Flac => "flacenc",
+            AudioCodec::None | AudioCodec::Auto => return Ok(None),
+        };
+
+        if self.profile == Profile::Y4m && self.muxer.is_none() {
+            Err(UnsupportedSetting { profile: self.profile.name(), setting: "audio" })?;
+        }
+
+        Ok(Some(make_element(encoder)?))
+    }
+
+    /// File extension matching the container
+    pub fn extension(&self) -> &'static str {
+        match self.muxer.as_ref().map(String::as_ref) {
+            Some("matroskamux") => return "mkv",
+            Some("webmmux") => return "webm",
+            Some("mp4mux") | Some("qtmux") => return "mp4",
+            Some("mpegtsmux") => return "ts",
+            _ => (),
+        }
+
+        match self.profile {
+            Profile::H264 | Profile::H264Lossless | Profile::H265 | Profile::Custom => "mp4",
+            Profile::Ffv1 => "mkv",
+            Profile::Vp9 | Profile::Av1 => "webm",
+            Profile::Y4m => "y4m",
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use failure::Error;
+use serde_json;
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use config::Config;
+use conform::Conform;
+use discover;
+use encoding::Profile;
+use package::{self, Packaging};
+use prepare;
+
+/// Length of a GOP, and so of a segment, when the ladder doesn't get a
+/// keyframe interval
+const DEFAULT_GOP_SECONDS: f64 = 2.0;
+
+/// One resolution and bitrate pair of the ladder
+#[derive(Debug, Clone, Serialize)]
+pub struct Rendition {
+    pub width: i32,
+    pub height: i32,
+    /// kbit/s
+    pub bitrate: u32,
+}
+
+impl Rendition {
+    /// Parses a comma separated list of WIDTHxHEIGHT@KBPS
+    pub fn parse_ladder(value: &str) -> Result<Vec<Rendition>, String> {
+        value.split(',')
+            .map(|rendition| {
+                let invalid = || format!("'{}' is not a rendition like 1280x720@2500", rendition);
+                let mut parts = rendition.trim().splitn(2, '@');
+                let (width, height) = Conform::parse_size(parts.next().unwrap()).map_err(|_| invalid())?;
+                let bitrate = parts.next().ok_or_else(invalid)?.parse::<u32>().map_err(|_| invalid())?;
+                if bitrate == 0 {
+                    return Err(invalid());
+                }
+                Ok(Rendition { width, height, bitrate })
+            })
+            .collect()
+    }
+
+    /// Renditions are only switchable if their keyframes line up, which
+    /// only the H.264 encoder is set up for
+    pub fn check_profile(profile: Profile) -> Result<(), String> {
+        match profile {
+            Profile::H264 => Ok(()),
+            profile => Err(format!("A ladder needs the h264 profile, not {}", profile.name())),
+        }
+    }
+
+    /// Id written into the frame ids of the rendition, after the segment
+    /// prefix
+    pub fn id(index: usize) -> String {
+        format!("r{}", index)
+    }
+}
+
+/// What was written for each rendition, for whoever aligns captures of the
+/// ladder
+#[derive(Serialize)]
+struct RenditionOutput<'a> {
+    id: String,
+    rendition: &'a Rendition,
+    output: String,
+    start_prefix: String,
+    content_prefix: String,
+    end_prefix: String,
+}
+
+#[derive(Serialize)]
+struct LadderFile<'a> {
+    input: &'a str,
+    packaging: Option<Packaging>,
+    renditions: Vec<RenditionOutput<'a>>,
+}
+
+/// Prepares every rendition of `config.ladder` into the `config.output`
+/// directory, then packages them if asked to
+pub fn run(config: &Config) -> Result<(), Error> {
+    fs::create_dir_all(&config.output)?;
+
+    // Renditions are only switchable at keyframes shared by all of them
+    let media = discover::discover(&config.input)?;
+    let framerate = config.conform.target(&config.input, &media.video)?.framerate;
+    let fps = *framerate.numer() as f64 / *framerate.denom() as f64;
+    let keyframe_interval = config.encoding.keyframe_interval
+        .unwrap_or_else(|| (DEFAULT_GOP_SECONDS * fps).ceil() as u32);
+    // Segments can only be cut at those keyframes, so they're whole GOPs.
+    // The target duration only has to be their length to the nearest
+    // second, rounding it up would announce longer segments than there are.
+    let segment_duration = (keyframe_interval as f64 / fps).round().max(1.0) as u32;
+
+    let mut outputs = Vec::new();
+    for (index, rendition) in config.ladder.iter().enumerate() {
+        let id = Rendition::id(index);
+        let mut job = config.clone();
+        job.ladder = Vec::new();
+        job.conform.size = Some((rendition.width, rendition.height));
+        job.encoding.bitrate = Some(rendition.bitrate);
+        job.encoding.quality = None;
+        job.encoding.keyframe_interval = Some(keyframe_interval);
+        job.encoding.fixed_gop = true;
+        job.start_prefix = format!("{}{}:", config.start_prefix, id);
+        job.content_prefix = format!("{}{}:", config.content_prefix, id);
+        job.end_prefix = format!("{}{}:", config.end_prefix, id);
+        job.output = Path::new(&config.output)
+            .join(format!("{}.{}", id, config.encoding.extension()))
+            .to_string_lossy()
+            .into_owned();
+
+        println!("Preparing rendition {} ({}x{} at {} kbit/s)", id, rendition.width, rendition.height, rendition.bitrate);
+        prepare(&job)?;
+
+        outputs.push(RenditionOutput {
+            id,
+            rendition,
+            output: job.output,
+            start_prefix: job.start_prefix,
+            content_prefix: job.content_prefix,
+            end_prefix: job.end_prefix,
+        });
+    }
+
+    if let Some(packaging) = config.packaging {
+        let files: Vec<(&str, &Rendition)> = outputs.iter().map(|o| (o.output.as_ref(), o.rendition)).collect();
+        package::package(packaging, &config.output, &files, segment_duration)?;
+    }
+
+    let path = Path::new(&config.output).join("ladder.json");
+    serde_json::to_writer_pretty(BufWriter::new(File::create(&path)?), &LadderFile {
+        input: &config.input,
+        packaging: config.packaging,
+        renditions: outputs,
+    })?;
+    println!("Wrote ladder description to {}", path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renditions(value: &str) -> Result<Vec<(i32, i32, u32)>, String> {
+        Rendition::parse_ladder(value).map(|ladder| ladder.iter().map(|r| (r.width, r.height, r.bitrate)).collect())
+    }
+
+    #[test]
+    fn ladder_of_renditions() {
+        assert_eq!(renditions("1920x1080@6000, 640x360@800"), Ok(vec![(1920, 1080, 6000), (640, 360, 800)]));
+    }
+
+    #[test]
+    fn errors_name_the_rendition() {
+        assert_eq!(renditions("1280x720@2500,640x360"),
+                   Err("'640x360' is not a rendition like 1280x720@2500".to_owned()));
+    }
+
+    #[test]
+    fn renditions_need_an_even_size_and_a_bitrate() {
+        assert_eq!(renditions("2x2@1"), Ok(vec![(2, 2, 1)]));
+        assert!(renditions("1281x720@2500").is_err());
+        assert!(renditions("1280x0@2500").is_err());
+        assert!(renditions("1280x720@0").is_err());
+    }
+}
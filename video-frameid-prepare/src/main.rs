@@ -2,21 +2,60 @@ extern crate gstreamer as gst;
 use gst::prelude::*;
 extern crate gstreamer_video as gst_video;
 extern crate gstreamer_app as gst_app;
+extern crate gstreamer_pbutils as gst_pbutils;
+extern crate gstreamer_rtsp_server as gst_rtsp_server;
 
 extern crate glib;
 
+extern crate clap;
+extern crate frameid_common;
+extern crate glob;
+extern crate num_cpus;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
+
+#[macro_use]
 extern crate failure;
 use failure::Error;
 
-use std::env;
 use std::error::Error as StdError;
+use std::process;
 
 #[macro_use]
 extern crate failure_derive;
 
+mod batch;
+mod chunks;
+mod config;
+mod conform;
+mod discover;
+mod encoding;
+mod ladder;
+mod manifest;
+mod package;
+mod playback;
+mod pool;
+mod progress;
+mod segments;
+mod stream;
+mod trim;
+mod verify;
+
+use config::Config;
+use discover::{AudioFormat, MediaInfo, VideoGeometry};
+use frameid_common::interrupt::{self, Interrupted};
+use manifest::{FrameLog, Manifest, Segment, SourceFrames};
+use progress::{Progress, ProgressMode};
+use segments::SegmentContent;
+use stream::StreamServer;
+use trim::{EmptyRange, PendingSeek};
+
 #[derive(Debug, Fail)]
 #[fail(display = "Missing element {}", _0)]
-struct MissingElement(&'static str);
+struct MissingElement(String);
 
 #[derive(Debug, Fail)]
 #[fail(display = "Received error from {}: {} (debug: {:?})", src, error, debug)]
@@ -25,95 +64,183 @@ struct ErrorMessage {
     error: String,
     debug: Option<String>,
     #[cause] cause: glib::Error,
+    /// Already written out as a JSON progress line
+    reported: bool,
 }
 
-#[derive(Debug)]
-struct Config {
-    input: String,
-    output: String,
-}
+/// How often the main loop checks for interruptions and progress
+const POLL_INTERVAL_MILLISECONDS: u64 = 100;
 
-impl Config {
-    fn new(args: &[String]) -> Result<Config, &'static str> {
-        let input = args[1].clone();
-        let output = args[2].clone();
+/// Size of the buffers of silence or tone around the inputs' audio
+const AUDIO_FILLER_SAMPLES_PER_BUFFER: u64 = 1024;
 
-        Ok(Config {input, output})
-    }
+/// One of the inputs, in the order they are played
+struct Clip {
+    index: usize,
+    uri: String,
+    prefix: String,
+    media: MediaInfo,
+}
+
+fn make_element(factory : &str) -> Result<gst::Element, MissingElement> {
+    gst::ElementFactory::make(factory, None).ok_or_else(|| MissingElement(factory.to_owned()))
 }
 
-// TODO This should be discovered by the input file
-const WIDTH: usize = 1280;
-const HEIGHT: usize = 720;
-const FRAMERATE_NUM: usize = 30000;
-const FRAMERATE_DEN: usize = 1001;
+// Pre-roll and post-roll segments, converted to the input's caps and tagged
+// with their own prefix
+fn setup_segment_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad, segment : Segment, content : &SegmentContent, prefix : &str, frames : u64, config : &Config, geometry : &VideoGeometry, log : FrameLog) -> Result<bool, Error> {
+    let src = segments::setup_source(pipeline, content, geometry, frames)?;
+    let videoconvert = make_element("videoconvert")?;
+    let videoscale = make_element("videoscale")?;
+    let videorate = make_element("videorate")?;
+    let scaledcapsfilter = make_element("capsfilter")?;
+    let frameid = make_element("rsframeid")?;
+    let frameidvideoconvert = make_element("videoconvert")?;
+    let capsfilter = make_element("capsfilter")?;
+
+    scaledcapsfilter.set_property("caps", &geometry.scaled_caps())?;
+    frameid.set_property("prefix", &prefix)?;
+    frameid.set_property("position", &config.position)?;
+    capsfilter.set_property("caps", &geometry.caps())?;
+
+    pipeline.add_many(&[&videoconvert, &videoscale, &videorate, &scaledcapsfilter, &frameid, &frameidvideoconvert, &capsfilter])?;
+    gst::Element::link_many(&[&src, &videoconvert, &videoscale, &videorate, &scaledcapsfilter, &frameid, &frameidvideoconvert, &capsfilter])?;
 
-const PREFRAMES: i32 = 150;
+    segments::limit_frames(&scaledcapsfilter.get_static_pad("src").unwrap(), frames);
+    manifest::record_frames(&frameid.get_static_pad("sink").unwrap(), segment, None, prefix, 0, None, log);
 
-fn setup_prepend_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad) -> Result<bool, Error> {
-    let src = gst::ElementFactory::make("videotestsrc", None).ok_or(MissingElement("videotestsrc"))?;
-    let frameid = gst::ElementFactory::make("rsframeid", None).ok_or(MissingElement("rsframeid"))?;
-    let srcconv = gst::ElementFactory::make("videoconvert", None).ok_or(MissingElement("videoconvert"))?;
-    let srccapsfilter = gst::ElementFactory::make("capsfilter", None).ok_or(MissingElement("capsfilter"))?;
-    let srcenc = gst::ElementFactory::make("x264enc", None).ok_or(MissingElement("x264enc"))?;
+    assert_eq!(capsfilter.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
 
-    src.set_property("num-buffers", &PREFRAMES)?;
-    frameid.set_property("prefix", &"s:".to_owned())?;
-    frameid.set_property("position", &"bottom-right".to_owned())?;
-    srccapsfilter.set_property("caps", &gst::Caps::from_string(&format!("video/x-raw, format=(string)I420, width=(int){}, height=(int){}, framerate=(fraction){}/{}",
-            WIDTH, HEIGHT, FRAMERATE_NUM, FRAMERATE_DEN)))?;
+    Ok(true)
+}
+
+// Fills a pre-roll or post-roll segment, or a clip without audio, with silence
+// or a tone lasting exactly as long as its video, so both concats switch to the
+// next segment together.
+fn setup_audio_filler_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad, wave : &str, audio : &AudioFormat, geometry : &VideoGeometry, frames : u64) -> Result<bool, Error> {
+    let samples = audio.samples_for(frames, geometry.framerate);
+
+    // The last buffer is cut to the exact number of samples, so the audio
+    // ends with the video whatever the buffer size
+    let buffers = (samples + AUDIO_FILLER_SAMPLES_PER_BUFFER - 1) / AUDIO_FILLER_SAMPLES_PER_BUFFER;
+    let src = gst::parse_launch(&format!("audiotestsrc wave={} samplesperbuffer={} num-buffers={}",
+                                         wave, AUDIO_FILLER_SAMPLES_PER_BUFFER, buffers))?;
+    let capsfilter = make_element("capsfilter")?;
+
+    capsfilter.set_property("caps", &audio.caps())?;
 
-    pipeline.add_many(&[&src, &frameid, &srcconv, &srccapsfilter, &srcenc])?;
-    gst::Element::link_many(&[&src, &frameid, &srcconv, &srccapsfilter, &srcenc])?;
+    pipeline.add_many(&[&src, &capsfilter])?;
+    src.link(&capsfilter)?;
 
-    assert_eq!(srcenc.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
+    segments::limit_samples(&capsfilter.get_static_pad("src").unwrap(), samples, audio);
+    assert_eq!(capsfilter.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
+    capsfilter.sync_state_with_parent()?;
+    src.sync_state_with_parent()?;
 
     Ok(true)
 }
 
-fn setup_decoder_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad, config : &Config) -> Result<bool, Error> {
-    let uridec = gst::ElementFactory::make("uridecodebin", None).ok_or(MissingElement("uridecodebin"))?;
+fn link_decoded_audio(pipeline : &gst::Pipeline, src_pad : &gst::Pad, sink_pad : &gst::Pad, caps : &gst::Caps) -> Result<(), Error> {
+    let queue = make_element("queue")?;
+    let audioconvert = make_element("audioconvert")?;
+    let audioresample = make_element("audioresample")?;
+    let capsfilter = make_element("capsfilter")?;
 
-    uridec.set_property("uri", &glib::Value::from(&config.input))?;
+    capsfilter.set_property("caps", caps)?;
+
+    pipeline.add_many(&[&queue, &audioconvert, &audioresample, &capsfilter])?;
+    gst::Element::link_many(&[&queue, &audioconvert, &audioresample, &capsfilter])?;
+    capsfilter.sync_state_with_parent()?;
+    audioresample.sync_state_with_parent()?;
+    audioconvert.sync_state_with_parent()?;
+    queue.sync_state_with_parent()?;
+
+    assert_eq!(capsfilter.get_static_pad("src").unwrap().link(sink_pad), gst::PadLinkReturn::Ok);
+    assert_eq!(src_pad.link(&queue.get_static_pad("sink").unwrap()), gst::PadLinkReturn::Ok);
+
+    Ok(())
+}
+
+fn setup_decoder_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad, audio_sink_pad : Option<gst::Pad>, clip : &Clip, config : &Config, geometry : &VideoGeometry, audio_caps : Option<gst::Caps>, log : FrameLog, sources : SourceFrames, pending_seek : Option<PendingSeek>) -> Result<bool, Error> {
+    let uridec = make_element("uridecodebin")?;
+
+    uridec.set_property("uri", &glib::Value::from(&clip.uri))?;
     pipeline.add(&uridec)?;
 
     let pipeline_clone = pipeline.clone();
+    let scaled_caps = geometry.scaled_caps();
+    let caps = geometry.caps();
+    let index = clip.index;
+    let prefix = clip.prefix.clone();
+    let position = config.position.clone();
+    let first_index = config.trim.first_index(geometry.framerate);
+    let (crop_left, crop_right, crop_top, crop_bottom) = config.conform.crop(&clip.media.video, geometry);
+    let add_borders = config.conform.fit == conform::Fit::Letterbox;
     uridec.connect_pad_added(move |_, src_pad| {
         // FIXME post an error message if any of those fail instead of just doing unwrap()
-        if !src_pad.get_current_caps().unwrap().get_structure(0).unwrap().get_name().contains("video") {
+        let pad_caps = src_pad.get_current_caps().unwrap();
+        let name = pad_caps.get_structure(0).unwrap().get_name();
+        if name.starts_with("audio/") {
+            // Only the first audio stream is kept
+            if let (Some(ref audio_sink_pad), Some(ref audio_caps)) = (audio_sink_pad.as_ref(), audio_caps.as_ref()) {
+                if !audio_sink_pad.is_linked() {
+                    link_decoded_audio(&pipeline_clone, src_pad, audio_sink_pad, audio_caps).unwrap();
+                }
+            }
+            return;
+        }
+        if !name.contains("video") {
             return;
         }
         let queue = gst::ElementFactory::make("queue", None).unwrap();
-        let enc = gst::ElementFactory::make("x264enc", None).unwrap();
-        // blitting to the decoded file
-        let frameidcf = gst::ElementFactory::make("capsfilter", None).unwrap();
+        // everything is normalised to the caps of the first clip, or the
+        // conformed caps
+        let videocrop = gst::ElementFactory::make("videocrop", None).unwrap();
         let videoconvert = gst::ElementFactory::make("videoconvert", None).unwrap();
+        let videoscale = gst::ElementFactory::make("videoscale", None).unwrap();
+        let videorate = gst::ElementFactory::make("videorate", None).unwrap();
+        let scaledcf = gst::ElementFactory::make("capsfilter", None).unwrap();
+        // blitting to the decoded file
         let frameid = gst::ElementFactory::make("rsframeid", None).unwrap();
         let frameidvideoconvert = gst::ElementFactory::make("videoconvert", None).unwrap();
+        let frameidcf = gst::ElementFactory::make("capsfilter", None).unwrap();
 
-        frameidcf.set_property("caps", &gst::Caps::from_string("video/x-raw, format=(string)I420")).unwrap();
+        videocrop.set_property("left", &crop_left).unwrap();
+        videocrop.set_property("right", &crop_right).unwrap();
+        videocrop.set_property("top", &crop_top).unwrap();
+        videocrop.set_property("bottom", &crop_bottom).unwrap();
+        videoscale.set_property("add-borders", &add_borders).unwrap();
+        scaledcf.set_property("caps", &scaled_caps).unwrap();
+        frameidcf.set_property("caps", &caps).unwrap();
 
         let pipeline = &pipeline_clone;
 
-        pipeline.add(&queue).unwrap();
-        pipeline.add(&frameidcf).unwrap();
-        pipeline.add(&videoconvert).unwrap();
-        pipeline.add(&frameid).unwrap();
-        pipeline.add(&frameidvideoconvert).unwrap();
-        pipeline.add(&enc).unwrap();
-        gst::Element::link_many(&[&queue, &videoconvert, &frameid, &frameidvideoconvert, &frameidcf, &enc]).unwrap();
-        enc.sync_state_with_parent().unwrap();
+        pipeline.add_many(&[&queue, &videocrop, &videoconvert, &videoscale, &videorate, &scaledcf,
+                          &frameid, &frameidvideoconvert, &frameidcf]).unwrap();
+        gst::Element::link_many(&[&queue, &videocrop, &videoconvert, &videoscale, &videorate, &scaledcf,
+                                &frameid, &frameidvideoconvert, &frameidcf]).unwrap();
         frameidcf.sync_state_with_parent().unwrap();
         frameidvideoconvert.sync_state_with_parent().unwrap();
         frameid.sync_state_with_parent().unwrap();
+        scaledcf.sync_state_with_parent().unwrap();
+        videorate.sync_state_with_parent().unwrap();
+        videoscale.sync_state_with_parent().unwrap();
         videoconvert.sync_state_with_parent().unwrap();
+        videocrop.sync_state_with_parent().unwrap();
         queue.sync_state_with_parent().unwrap();
-        frameid.set_property("prefix", &"f:".to_owned()).unwrap();
-        frameid.set_property("position", &"bottom-right".to_owned()).unwrap();
+        frameid.set_property("prefix", &prefix).unwrap();
+        frameid.set_property("position", &position).unwrap();
+        frameid.set_property("first-index", &first_index).unwrap();
+        manifest::record_source_frames(&videorate.get_static_pad("sink").unwrap(), sources.clone());
+        manifest::record_frames(&frameid.get_static_pad("sink").unwrap(), Segment::Content, Some(index), &prefix, first_index,
+                                Some(sources.clone()), log.clone());
 
-        assert_eq!(enc.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
+        assert_eq!(frameidcf.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
 
         let queue_sink_pad = queue.get_static_pad("sink").unwrap();
+        if let Some(ref pending_seek) = pending_seek {
+            pending_seek.seek_from(&queue_sink_pad);
+        }
         assert_eq!(src_pad.link(&queue_sink_pad), gst::PadLinkReturn::Ok);
 
     });
@@ -121,100 +248,290 @@ fn setup_decoder_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad, config :
     Ok(true)
 }
 
-fn setup_append_branch(pipeline : &gst::Pipeline, sink_pad : gst::Pad) -> Result<bool, Error> {
-    // Prepare the last concat
-    let videotestsrc = gst::ElementFactory::make("videotestsrc", None).ok_or(MissingElement("videotestsrc"))?;
-    let lastframeid = gst::ElementFactory::make("rsframeid", None).ok_or(MissingElement("rsframeid"))?;
-    let lastvideoconvert = gst::ElementFactory::make("videoconvert", None).ok_or(MissingElement("videoconvert"))?;
-    let lastcapsfilter = gst::ElementFactory::make("capsfilter", None).ok_or(MissingElement("capsfilter"))?;
-    let lastenc = gst::ElementFactory::make("x264enc", None).ok_or(MissingElement("x264enc"))?;
-
-    lastcapsfilter.set_property("caps", &gst::Caps::from_string(&format!("video/x-raw, format=(string)I420, width=(int){}, height=(int){}, framerate=(fraction){}/{}",
-            WIDTH, HEIGHT, FRAMERATE_NUM, FRAMERATE_DEN)))?;
-    videotestsrc.set_property("num-buffers", &PREFRAMES)?;
-    lastframeid.set_property("prefix", &"e:".to_owned())?;
-    lastframeid.set_property("position", &"bottom-right".to_owned())?;
-
-    pipeline.add_many(&[&videotestsrc, &lastframeid, &lastvideoconvert, &lastcapsfilter, &lastenc])?;
-    gst::Element::link_many(&[&videotestsrc, &lastframeid, &lastvideoconvert, &lastcapsfilter, &lastenc])?;
-
-    assert_eq!(lastenc.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
-    lastenc.sync_state_with_parent()?;
-    lastcapsfilter.sync_state_with_parent()?;
-    lastvideoconvert.sync_state_with_parent()?;
-    lastframeid.sync_state_with_parent()?;
-    videotestsrc.sync_state_with_parent()?;
+// Encoded video (and audio) muxed into the output file
+fn setup_file_output(pipeline : &gst::Pipeline, config : &Config, enc : &gst::Element, audio_enc : Option<&gst::Element>) -> Result<(), Error> {
+    let mux = config.encoding.make_muxer()?;
+    let sink = make_element("filesink")?;
 
-    Ok(true)
+    sink.set_property("location", &config.output)?;
+
+    pipeline.add(&sink)?;
+    match mux {
+        Some(ref mux) => {
+            pipeline.add(mux)?;
+            gst::Element::link_many(&[enc, mux, &sink])?;
+        }
+        None => enc.link(&sink)?,
+    }
+    if let Some(audio_enc) = audio_enc {
+        audio_enc.link(mux.as_ref().unwrap())?;
+    }
+
+    Ok(())
 }
 
-fn create_pipeline(config : Config) -> Result<(gst::Pipeline), Error> {
-    gst::init()?;
+fn create_pipeline(config : &Config, manifest : &mut Manifest) -> Result<(gst::Pipeline, Option<StreamServer>), Error> {
+    let clips = config.inputs()
+        .iter()
+        .enumerate()
+        .map(|(index, uri)| {
+            // A single input keeps the plain prefix, otherwise the clip id is part of it
+            let prefix = if config.append.is_empty() {
+                config.content_prefix.clone()
+            } else {
+                format!("{}{}:", config.content_prefix, index)
+            };
+            Ok(Clip { index, uri: uri.to_string(), prefix, media: discover::discover(uri)? })
+        })
+        .collect::<Result<Vec<Clip>, Error>>()?;
+
+    // Everything is converted to the first input's caps (or the conformed
+    // ones), so the test segments have to match them exactly or concat would
+    // renegotiate
+    let geometry = &config.conform.target(&clips[0].uri, &clips[0].media.video)?;
+    let audio = clips.iter().filter_map(|clip| clip.media.audio.as_ref()).next();
+    manifest.set_caps(&geometry.caps());
 
     let pipeline = gst::Pipeline::new(None);
 
-    // end of the pipeline responsible of mixing it up together
-    let concat = gst::ElementFactory::make("concat", None).ok_or(MissingElement("concat"))?;
-    let mux =
-        gst::ElementFactory::make("mp4mux", None).ok_or(MissingElement("mp4mux"))?;
-    let sink =
-        gst::ElementFactory::make("filesink", None).ok_or(MissingElement("filesink"))?;
+    // end of the pipeline responsible of mixing it up together. All branches
+    // deliver raw video (and audio) with the same caps so a single encoder sees
+    // the whole sequence.
+    let concat = make_element("concat")?;
+    pipeline.add(&concat)?;
+
+    // Audio gets its own concat, fed by silence or a tone around the inputs' audio
+    let audio_enc = match audio {
+        Some(_) if config.play.is_none() && config.stream.map_or(true, |kind| kind.has_audio()) => {
+            config.encoding.make_audio_encoder()?
+        }
+        _ => None,
+    };
+    if let Some(ref audio_enc) = audio_enc {
+        pipeline.add(audio_enc)?;
+    }
 
-    pipeline.add_many(&[&concat, &mux, &sink])?;
-    gst::Element::link_many(&[&mux, &sink])?;
+    let server = if let Some(ref video_sink) = config.play {
+        manifest.set_presentation(playback::setup_playback(&pipeline, &concat, video_sink)?);
+        None
+    } else {
+        let enc = config.encoding.make_encoder()?;
+        pipeline.add(&enc)?;
+        if config.stream.is_some() {
+            // Nothing downstream is live, so the raw frames are paced here
+            let pace = make_element("identity")?;
+            pace.set_property("sync", &true)?;
+            pipeline.add(&pace)?;
+            gst::Element::link_many(&[&concat, &pace, &enc])?;
+        } else {
+            concat.link(&enc)?;
+        }
 
-    // Source and destination
-    sink.set_property("location", &config.output).unwrap();
+        match config.stream {
+            Some(kind) => Some(stream::setup_stream(&pipeline, kind, &config.output, config.hls_port, &config.encoding,
+                                                    &enc, audio_enc.as_ref())?),
+            None => {
+                setup_file_output(&pipeline, config, &enc, audio_enc.as_ref())?;
+                None
+            }
+        }
+    };
+
+    let audio_concat = match audio_enc {
+        Some(ref audio_enc) => {
+            let audio_concat = make_element("concat")?;
+            pipeline.add(&audio_concat)?;
+            audio_concat.link(audio_enc)?;
+            Some(audio_concat)
+        }
+        None => None,
+    };
+    let audio_sink_pad = || audio_concat.as_ref().map(|concat| concat.get_request_pad("sink_%u").unwrap());
+
+    let preroll_frames = config.preroll.frames(geometry.framerate);
+    let postroll_frames = config.postroll.frames(geometry.framerate);
+    let content_frames: u64 = clips.iter()
+        .map(|clip| config.trim.frames(clip.media.frames(geometry.framerate), geometry.framerate))
+        .sum();
+    // Catches an --end before --start in different units, or past the input
+    if config.trim.is_set() && content_frames == 0 {
+        Err(EmptyRange(clips[0].uri.clone()))?;
+    }
+    manifest.set_expected_frames(preroll_frames + content_frames + postroll_frames, geometry.framerate);
+
+    // Chunks in the middle of a chunked input have neither
+    if preroll_frames > 0 {
+        let pre_log = manifest.log(Segment::Pre);
+        setup_segment_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap(), Segment::Pre, &config.preroll_content,
+                             &config.start_prefix, preroll_frames, config, geometry, pre_log)?;
+        if let Some(pad) = audio_sink_pad() {
+            setup_audio_filler_branch(&pipeline, pad, &config.audio_filler, audio.unwrap(), geometry, preroll_frames)?;
+        }
+    }
 
-    setup_prepend_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap())?;
-    setup_decoder_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap(), &config)?;
-    setup_append_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap())?;
+    for clip in &clips {
+        let (log, sources) = manifest.add_clip(&clip.uri, config.trim.first_index(geometry.framerate));
+        let clip_audio_pad = match clip.media.audio {
+            Some(_) => audio_sink_pad(),
+            None => None,
+        };
+        // Only a single input can be trimmed
+        let clip_seek = if config.trim.is_set() {
+            Some(PendingSeek::new(&config.trim, geometry.framerate))
+        } else {
+            None
+        };
+        setup_decoder_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap(), clip_audio_pad, clip, config,
+                             geometry, audio.map(AudioFormat::caps), log, sources, clip_seek)?;
+
+        // Clips without audio get silence so the following clips stay in sync
+        if clip.media.audio.is_none() {
+            if let Some(pad) = audio_sink_pad() {
+                setup_audio_filler_branch(&pipeline, pad, "silence", audio.unwrap(), geometry, clip.media.frames(geometry.framerate))?;
+            }
+        }
+    }
 
-    let mux_sinkpad = mux.get_request_pad("video_%u").unwrap();
-    let concat_srcpad = concat.get_static_pad("src").unwrap();
-    assert_eq!(concat_srcpad.link(&mux_sinkpad), gst::PadLinkReturn::Ok);
+    if postroll_frames > 0 {
+        let post_log = manifest.log(Segment::Post);
+        setup_segment_branch(&pipeline, concat.get_request_pad("sink_%u").unwrap(), Segment::Post, &config.postroll_content,
+                             &config.end_prefix, postroll_frames, config, geometry, post_log)?;
+        if let Some(pad) = audio_sink_pad() {
+            setup_audio_filler_branch(&pipeline, pad, &config.audio_filler, audio.unwrap(), geometry, postroll_frames)?;
+        }
+    }
 
-    Ok(pipeline)
+    Ok((pipeline, server))
 }
 
-fn main_loop(pipeline: gst::Pipeline) -> Result<(), Error> {
+fn main_loop(pipeline: gst::Pipeline, mut progress: Option<&mut Progress>) -> Result<(), Error> {
     pipeline.set_state(gst::State::Playing).into_result()?;
 
     let bus = pipeline
         .get_bus()
         .expect("Pipeline without bus. Shouldn't happen!");
+    let interval = gst::ClockTime::from_mseconds(POLL_INTERVAL_MILLISECONDS);
+    let mut eos_sent = false;
 
-    while let Some(msg) = bus.timed_pop(gst::CLOCK_TIME_NONE) {
+    loop {
         use gst::MessageView;
 
+        if let Some(ref mut progress) = progress {
+            progress.update(&pipeline);
+        }
+        // The sources stop and concat moves through its remaining (now
+        // empty) branches, so the muxer still sees EOS and finalises the file
+        if interrupt::is_interrupted() && !eos_sent {
+            pipeline.send_event(gst::Event::new_eos().build());
+            eos_sent = true;
+        }
+
+        let msg = match bus.timed_pop(interval) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
         match msg.view() {
             MessageView::Eos(..) => break,
             MessageView::Error(err) => {
                 pipeline.set_state(gst::State::Null).into_result()?;
-                Err(ErrorMessage {
+                let mut error = ErrorMessage {
                     src: msg.get_src()
                         .map(|s| s.get_path_string())
                         .unwrap_or_else(|| String::from("None")),
                     error: err.get_error().description().into(),
                     debug: err.get_debug(),
                     cause: err.get_error(),
-                })?;
+                    reported: false,
+                };
+                if let Some(ref progress) = progress {
+                    progress.error(&error);
+                    error.reported = progress.mode() == ProgressMode::Json;
+                }
+                Err(error)?;
             }
             _ => (),
         }
     }
 
     pipeline.set_state(gst::State::Null).into_result()?;
+    if let Some(ref progress) = progress {
+        progress.finish();
+    }
 
     Ok(())
 }
 
+/// Runs the pipeline preparing `config`, returning what went into the output
+fn run_prepare(config : &Config) -> Result<Manifest, Error> {
+    let mut manifest = Manifest::new();
+    let (pipeline, server) = create_pipeline(config, &mut manifest)?;
+    if let Some(ref server) = server {
+        server.wait_for_client()?;
+    }
+    let mut progress = Progress::new(config.progress, &config.input, &manifest);
+    main_loop(pipeline, Some(&mut progress))?;
+
+    Ok(manifest)
+}
+
+/// Writes the manifest of a finished output and verifies it if asked to
+fn finish_output(config : &Config, manifest : &Manifest) -> Result<(), Error> {
+    // The manifest still describes what made it into an interrupted output
+    if let Some(path) = manifest.write(config, &config.manifest_format)? {
+        println!("Wrote manifest to {}", path);
+    }
+    if let Some(presentation) = manifest.presentation() {
+        let path = playback::write(config, &manifest.frames(), &presentation)?;
+        println!("Wrote presentation log to {}", path);
+    }
+    if interrupt::is_interrupted() {
+        Err(Interrupted)?;
+    }
+    if config.verify {
+        verify::verify(config, &manifest.frames())?;
+    }
+
+    Ok(())
+}
+
+/// Prepares a single input into a single output
+fn prepare(config : &Config) -> Result<(), Error> {
+    if config.chunk_duration.is_some() {
+        return chunks::run(config);
+    }
+
+    let manifest = run_prepare(config)?;
+    finish_output(config, &manifest)
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args).unwrap();
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error! {}", e);
+            process::exit(1);
+        }
+    };
+
+    let result = gst::init().map_err(Error::from).and_then(|_| interrupt::install()).and_then(|_| {
+        if config.batch {
+            batch::run(&config)
+        } else if !config.ladder.is_empty() {
+            ladder::run(&config)
+        } else {
+            prepare(&config)
+        }
+    });
 
-    match create_pipeline(config).and_then(|pipeline| main_loop(pipeline)) {
+    match result {
         Ok(r) => r,
-        Err(e) => eprintln!("Error! {}", e),
+        Err(e) => {
+            // A plain line after the JSON one would break JSON lines readers
+            if !e.downcast_ref::<ErrorMessage>().map_or(false, |e| e.reported) {
+                eprintln!("Error! {}", e);
+            }
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,407 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+use serde_json;
+use sha1::Sha1;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use config::Config;
+use playback::PresentationLog;
+
+pub const FORMATS: [&str; 3] = ["json", "csv", "none"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Segment {
+    Pre,
+    Content,
+    Post,
+}
+
+impl Segment {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Segment::Pre => "pre",
+            Segment::Content => "content",
+            Segment::Post => "post",
+        }
+    }
+}
+
+/// One tagged frame, as it entered rsframeid
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameRecord {
+    pub segment: Segment,
+    /// Index of the input clip, for content frames
+    pub clip: Option<usize>,
+    pub prefix: String,
+    pub index: u64,
+    /// Timestamps in nanoseconds
+    pub pts: Option<u64>,
+    pub duration: Option<u64>,
+    /// Frame of the input this one was converted from, for content frames.
+    /// Counted from the first decoded frame, plus the first index of the clip.
+    pub source_frame: Option<u64>,
+    pub source_pts: Option<u64>,
+    /// SHA-1 of the frame before the qrcode was drawn on it
+    pub hash: String,
+}
+
+impl FrameRecord {
+    pub fn id(&self) -> String {
+        format!("{}{}", self.prefix, self.index)
+    }
+}
+
+pub type FrameLog = Arc<Mutex<Vec<FrameRecord>>>;
+
+/// Timestamps of the decoded frames of a clip, before framerate conversion
+pub type SourceFrames = Arc<Mutex<Vec<u64>>>;
+
+/// Where one input clip ended up in the output, in output frame numbers
+#[derive(Debug, Clone, Serialize)]
+pub struct ClipRange {
+    pub clip: usize,
+    pub input: String,
+    pub first_frame: u64,
+    pub frames: u64,
+    /// Ids of frames repeating the previous source frame
+    pub duplicated: Vec<String>,
+    /// Source frames that didn't make it into the output
+    pub dropped: Vec<u64>,
+}
+
+struct ClipLogs {
+    input: String,
+    first_index: u64,
+    log: FrameLog,
+    sources: SourceFrames,
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    input: &'a str,
+    caps: Option<&'a str>,
+    settings: &'a Config,
+    clips: Vec<ClipRange>,
+    frames: Vec<FrameRecord>,
+}
+
+/// Collects the frames of each segment while the pipeline runs
+pub struct Manifest {
+    caps: Option<String>,
+    framerate: Option<gst::Fraction>,
+    expected_frames: u64,
+    pre: FrameLog,
+    clips: Vec<ClipLogs>,
+    post: FrameLog,
+    /// When each frame was rendered, in playback mode
+    presentation: Option<PresentationLog>,
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest {
+            caps: None,
+            framerate: None,
+            expected_frames: 0,
+            pre: Arc::new(Mutex::new(Vec::new())),
+            clips: Vec::new(),
+            post: Arc::new(Mutex::new(Vec::new())),
+            presentation: None,
+        }
+    }
+
+    /// Joins the manifests of consecutive chunks of the same inputs. Content
+    /// frames of a chunk are numbered from where the previous one stopped,
+    /// so the logs of each clip are simply appended.
+    pub fn join(chunks: &[Manifest]) -> Manifest {
+        let mut manifest = Manifest::new();
+        if let (Some(first), Some(last)) = (chunks.first(), chunks.last()) {
+            manifest.caps = first.caps.clone();
+            manifest.framerate = first.framerate;
+            manifest.pre = first.pre.clone();
+            manifest.post = last.post.clone();
+        }
+
+        for chunk in chunks {
+            manifest.expected_frames += chunk.expected_frames;
+            for (n, clip) in chunk.clips.iter().enumerate() {
+                if manifest.clips.len() <= n {
+                    manifest.add_clip(&clip.input, clip.first_index);
+                }
+                let joined = &manifest.clips[n];
+                joined.log.lock().unwrap().extend(clip.log.lock().unwrap().iter().cloned());
+                joined.sources.lock().unwrap().extend(clip.sources.lock().unwrap().iter().cloned());
+            }
+        }
+
+        manifest
+    }
+
+    /// Logs for the next content clip, clips are expected in output order
+    pub fn add_clip(&mut self, input: &str, first_index: u64) -> (FrameLog, SourceFrames) {
+        let logs = ClipLogs {
+            input: input.to_owned(),
+            first_index,
+            log: Arc::new(Mutex::new(Vec::new())),
+            sources: Arc::new(Mutex::new(Vec::new())),
+        };
+        let ret = (logs.log.clone(), logs.sources.clone());
+        self.clips.push(logs);
+        ret
+    }
+
+    pub fn clips(&self) -> Vec<ClipRange> {
+        let mut first_frame = self.pre.lock().unwrap().len() as u64;
+
+        self.clips.iter()
+            .enumerate()
+            .map(|(clip, logs)| {
+                let log = logs.log.lock().unwrap();
+                let sources = logs.sources.lock().unwrap().len() as u64;
+
+                let mut duplicated = Vec::new();
+                let mut shown = vec![false; sources as usize];
+                let mut previous = None;
+                for frame in log.iter() {
+                    if let Some(source) = frame.source_frame {
+                        if previous == Some(source) {
+                            duplicated.push(frame.id());
+                        }
+                        if let Some(shown) = shown.get_mut((source - logs.first_index) as usize) {
+                            *shown = true;
+                        }
+                        previous = Some(source);
+                    }
+                }
+                let dropped = shown.iter()
+                    .enumerate()
+                    .filter(|&(_, shown)| !shown)
+                    .map(|(n, _)| logs.first_index + n as u64)
+                    .collect();
+
+                let frames = log.len() as u64;
+                let range = ClipRange {
+                    clip,
+                    input: logs.input.clone(),
+                    first_frame,
+                    frames,
+                    duplicated,
+                    dropped,
+                };
+                first_frame += frames;
+                range
+            })
+            .collect()
+    }
+
+    pub fn set_caps(&mut self, caps: &gst::Caps) {
+        self.caps = Some(caps.to_string());
+    }
+
+    pub fn set_presentation(&mut self, presentation: PresentationLog) {
+        self.presentation = Some(presentation);
+    }
+
+    pub fn presentation(&self) -> Option<PresentationLog> {
+        self.presentation.clone()
+    }
+
+    /// Number of frames the output should end up with, as far as the inputs'
+    /// containers know
+    pub fn set_expected_frames(&mut self, frames: u64, framerate: gst::Fraction) {
+        self.expected_frames = frames;
+        self.framerate = Some(framerate);
+    }
+
+    pub fn expected_frames(&self) -> u64 {
+        self.expected_frames
+    }
+
+    pub fn framerate(&self) -> Option<gst::Fraction> {
+        self.framerate
+    }
+
+    /// Every log, in output order
+    pub fn logs(&self) -> Vec<FrameLog> {
+        let mut logs = vec![self.pre.clone()];
+        logs.extend(self.clips.iter().map(|clip| clip.log.clone()));
+        logs.push(self.post.clone());
+        logs
+    }
+
+    /// Log of the pre-roll or post-roll segment
+    pub fn log(&self, segment: Segment) -> FrameLog {
+        match segment {
+            Segment::Pre => self.pre.clone(),
+            Segment::Post => self.post.clone(),
+            Segment::Content => panic!("content frames are logged per clip"),
+        }
+    }
+
+    /// All frames in output order
+    pub fn frames(&self) -> Vec<FrameRecord> {
+        let mut frames = self.pre.lock().unwrap().clone();
+        for clip in &self.clips {
+            frames.extend(clip.log.lock().unwrap().iter().cloned());
+        }
+        frames.extend(self.post.lock().unwrap().iter().cloned());
+        frames
+    }
+
+    /// Writes the manifest in `format` next to the output, returning its path
+    pub fn write(&self, config: &Config, format: &str) -> Result<Option<String>, Error> {
+        let path = match format {
+            "none" => return Ok(None),
+            format => format!("{}.manifest.{}", config.manifest_base(), format),
+        };
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        if format == "csv" {
+            self.write_csv(&mut writer, config)?;
+        } else {
+            serde_json::to_writer_pretty(&mut writer, &ManifestFile {
+                input: &config.input,
+                caps: self.caps.as_ref().map(String::as_ref),
+                settings: config,
+                clips: self.clips(),
+                frames: self.frames(),
+            })?;
+        }
+        writer.flush()?;
+
+        Ok(Some(path))
+    }
+
+    fn write_csv<W: Write>(&self, writer: &mut W, config: &Config) -> Result<(), Error> {
+        // CSV has no room for the metadata, keep it in comment lines
+        writeln!(writer, "# input: {}", config.input)?;
+        writeln!(writer, "# caps: {}", self.caps.as_ref().map(String::as_ref).unwrap_or(""))?;
+        writeln!(writer, "# settings: {}", serde_json::to_string(config)?)?;
+        for clip in self.clips() {
+            writeln!(writer, "# clip {}: {}, {} frames from output frame {}, {} duplicated, {} dropped",
+                     clip.clip, clip.input, clip.frames, clip.first_frame,
+                     clip.duplicated.len(), clip.dropped.len())?;
+        }
+        writeln!(writer, "segment,clip,prefix,index,id,pts,duration,source_frame,source_pts,hash")?;
+
+        let timestamp = |t: Option<u64>| t.map(|t| t.to_string()).unwrap_or_default();
+        for frame in self.frames() {
+            writeln!(writer, "{},{},\"{}\",{},\"{}\",{},{},{},{},{}",
+                     frame.segment.name(),
+                     frame.clip.map(|c| c.to_string()).unwrap_or_default(),
+                     frame.prefix.replace('"', "\"\""),
+                     frame.index,
+                     frame.id().replace('"', "\"\""),
+                     timestamp(frame.pts),
+                     timestamp(frame.duration),
+                     timestamp(frame.source_frame),
+                     timestamp(frame.source_pts),
+                     frame.hash)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Records the timestamp of every buffer passing `pad`, which has to be
+/// upstream of any framerate conversion
+pub fn record_source_frames(pad: &gst::Pad, sources: SourceFrames) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            if let Some(pts) = buffer.get_pts().nseconds() {
+                sources.lock().unwrap().push(pts);
+            }
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// The source frame closest to `pts`, which is the one videorate picks
+fn nearest_source(sources: &[u64], pts: u64) -> Option<(usize, u64)> {
+    let n = match sources.binary_search(&pts) {
+        Ok(n) => return Some((n, pts)),
+        Err(n) => n,
+    };
+
+    let before = if n > 0 { Some((n - 1, sources[n - 1])) } else { None };
+    let after = sources.get(n).map(|&s| (n, s));
+    match (before, after) {
+        (Some(b), Some(a)) => Some(if pts - b.1 <= a.1 - pts { b } else { a }),
+        (b, a) => b.or(a),
+    }
+}
+
+/// Records every buffer passing `pad`, which has to be upstream of the
+/// rsframeid element tagging the segment. With `sources` each frame is also
+/// mapped back to the input frame it was converted from.
+pub fn record_frames(pad: &gst::Pad, segment: Segment, clip: Option<usize>, prefix: &str, first_index: u64,
+                     sources: Option<SourceFrames>, log: FrameLog) {
+    let prefix = prefix.to_owned();
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            let hash = buffer.map_readable()
+                .map(|map| Sha1::from(map.as_slice()).digest().to_string())
+                .unwrap_or_default();
+
+            let pts = buffer.get_pts().nseconds();
+            let source = match (sources.as_ref(), pts) {
+                (Some(sources), Some(pts)) => nearest_source(&sources.lock().unwrap(), pts),
+                _ => None,
+            };
+
+            let mut log = log.lock().unwrap();
+            // rsframeid counts frames the same way
+            let index = first_index + log.len() as u64;
+            log.push(FrameRecord {
+                segment,
+                clip,
+                prefix: prefix.clone(),
+                index,
+                pts,
+                duration: buffer.get_duration().nseconds(),
+                source_frame: source.map(|(n, _)| first_index + n as u64),
+                source_pts: source.map(|(_, pts)| pts),
+                hash,
+            });
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_source_prefers_an_exact_match() {
+        assert_eq!(nearest_source(&[0, 40, 80], 40), Some((1, 40)));
+    }
+
+    #[test]
+    fn nearest_source_picks_the_closer_frame() {
+        assert_eq!(nearest_source(&[0, 40, 80], 30), Some((1, 40)));
+        assert_eq!(nearest_source(&[0, 40, 80], 50), Some((1, 40)));
+    }
+
+    #[test]
+    fn nearest_source_breaks_ties_towards_the_earlier_frame() {
+        assert_eq!(nearest_source(&[0, 40, 80], 20), Some((0, 0)));
+        assert_eq!(nearest_source(&[0, 40, 80], 60), Some((1, 40)));
+    }
+
+    #[test]
+    fn nearest_source_outside_the_sources() {
+        assert_eq!(nearest_source(&[10, 40], 0), Some((0, 10)));
+        assert_eq!(nearest_source(&[10, 40], 100), Some((1, 40)));
+        assert_eq!(nearest_source(&[], 100), None);
+    }
+}
@@ -0,0 +1,188 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use encoding::Profile;
+use ladder::Rendition;
+use main_loop;
+use make_element;
+
+pub const PACKAGINGS: [&str; 2] = ["hls", "dash"];
+
+/// Streaming formats the renditions of a ladder can be packaged in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Packaging {
+    /// A media playlist per rendition and a master playlist
+    Hls,
+    /// A single MPD with a representation per rendition
+    Dash,
+}
+
+impl Packaging {
+    pub fn from_name(name: &str) -> Option<Packaging> {
+        match name {
+            "hls" => Some(Packaging::Hls),
+            "dash" => Some(Packaging::Dash),
+            _ => None,
+        }
+    }
+
+    /// Packaging takes the H.264 out of the renditions without re-encoding
+    pub fn check_profile(&self, profile: Profile) -> Result<(), String> {
+        match profile {
+            Profile::H264 => Ok(()),
+            profile => Err(format!("Packaging needs the h264 profile, not {}", profile.name())),
+        }
+    }
+}
+
+/// Links every elementary stream of the prepared file at `path` through a
+/// queue (and a parser) to the pad `link` returns for its media type, if any
+pub fn add_parsed_source<F>(pipeline: &gst::Pipeline, path: &str, link: F) -> Result<(), Error>
+    where F: Fn(&str) -> Option<gst::Pad> + Send + Sync + 'static
+{
+    let filesrc = make_element("filesrc")?;
+    let parsebin = make_element("parsebin")?;
+
+    filesrc.set_property("location", &path)?;
+
+    pipeline.add_many(&[&filesrc, &parsebin])?;
+    filesrc.link(&parsebin)?;
+
+    let pipeline_clone = pipeline.clone();
+    parsebin.connect_pad_added(move |_, src_pad| {
+        let caps = src_pad.get_current_caps().unwrap();
+        let name = caps.get_structure(0).unwrap().get_name().to_owned();
+        let sink_pad = match link(&name) {
+            Some(pad) => pad,
+            None => return,
+        };
+
+        // The sinks mux several streams, which have to be able to run ahead
+        // of each other
+        let queue = gst::ElementFactory::make("queue", None).unwrap();
+        let mut elements = vec![queue];
+        if name == "video/x-h264" {
+            // hlssink2 only takes byte-stream, which MP4 doesn't store
+            elements.push(gst::ElementFactory::make("h264parse", None).unwrap());
+        }
+
+        let pipeline = &pipeline_clone;
+        for element in &elements {
+            pipeline.add(element).unwrap();
+        }
+        for pair in elements.windows(2) {
+            pair[0].link(&pair[1]).unwrap();
+        }
+        for element in elements.iter().rev() {
+            element.sync_state_with_parent().unwrap();
+        }
+
+        let last = elements.last().unwrap();
+        assert_eq!(last.get_static_pad("src").unwrap().link(&sink_pad), gst::PadLinkReturn::Ok);
+        assert_eq!(src_pad.link(&elements[0].get_static_pad("sink").unwrap()), gst::PadLinkReturn::Ok);
+    });
+
+    Ok(())
+}
+
+fn package_hls(directory: &Path, renditions: &[(&str, &Rendition)], segment_duration: u32) -> Result<(), Error> {
+    let pipeline = gst::Pipeline::new(None);
+
+    for (index, &(path, _)) in renditions.iter().enumerate() {
+        let rendition_directory = directory.join(Rendition::id(index));
+        fs::create_dir_all(&rendition_directory)?;
+
+        let sink = make_element("hlssink2")?;
+        sink.set_property("location", &rendition_directory.join("segment%05d.ts").to_string_lossy().into_owned())?;
+        sink.set_property("playlist-location", &rendition_directory.join("playlist.m3u8").to_string_lossy().into_owned())?;
+        sink.set_property("target-duration", &segment_duration)?;
+        // A complete VOD playlist
+        sink.set_property("max-files", &0u32)?;
+        sink.set_property("playlist-length", &0u32)?;
+        pipeline.add(&sink)?;
+
+        let linked = Arc::new(Mutex::new(Vec::new()));
+        add_parsed_source(&pipeline, path, move |name| {
+            let pad_name = if name.starts_with("video/") { "video" } else if name.starts_with("audio/") { "audio" } else { return None };
+            // Only the first stream of each type
+            let mut linked = linked.lock().unwrap();
+            if linked.contains(&pad_name) {
+                return None;
+            }
+            linked.push(pad_name);
+            sink.get_request_pad(pad_name)
+        })?;
+    }
+
+    main_loop(pipeline, None)?;
+
+    let mut master = BufWriter::new(File::create(directory.join("master.m3u8"))?);
+    writeln!(master, "#EXTM3U")?;
+    writeln!(master, "#EXT-X-VERSION:3")?;
+    for (index, &(_, rendition)) in renditions.iter().enumerate() {
+        writeln!(master, "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{}",
+                 rendition.bitrate * 1000, rendition.width, rendition.height)?;
+        writeln!(master, "{}/playlist.m3u8", Rendition::id(index))?;
+    }
+    master.flush()?;
+
+    println!("Wrote HLS master playlist to {}", directory.join("master.m3u8").display());
+
+    Ok(())
+}
+
+fn package_dash(directory: &Path, renditions: &[(&str, &Rendition)], segment_duration: u32) -> Result<(), Error> {
+    let pipeline = gst::Pipeline::new(None);
+    // Parsed so the muxer can be given by its nick
+    let sink = gst::parse_launch("dashsink muxer=mp4")?;
+
+    sink.set_property("mpd-root-path", &directory.to_string_lossy().into_owned())?;
+    sink.set_property("target-duration", &segment_duration)?;
+    pipeline.add(&sink)?;
+
+    for (index, &(path, _)) in renditions.iter().enumerate() {
+        let sink = sink.clone();
+        let audio_linked = Arc::new(Mutex::new(false));
+        add_parsed_source(&pipeline, path, move |name| {
+            if name.starts_with("video/") {
+                sink.get_request_pad("video_%u")
+            } else if name.starts_with("audio/") && index == 0 {
+                // All renditions share the audio of the first one
+                let mut audio_linked = audio_linked.lock().unwrap();
+                if *audio_linked {
+                    return None;
+                }
+                *audio_linked = true;
+                sink.get_request_pad("audio_%u")
+            } else {
+                None
+            }
+        })?;
+    }
+
+    main_loop(pipeline, None)?;
+
+    println!("Wrote DASH manifest to {}", directory.join("dash.mpd").display());
+
+    Ok(())
+}
+
+/// Packages the prepared renditions into `directory` without re-encoding
+/// them. Segments are cut at the first keyframe after `segment_duration`
+/// seconds, which all renditions have at the same frames.
+pub fn package(packaging: Packaging, directory: &str, renditions: &[(&str, &Rendition)], segment_duration: u32) -> Result<(), Error> {
+    let directory = Path::new(directory);
+
+    match packaging {
+        Packaging::Hls => package_hls(directory, renditions, segment_duration),
+        Packaging::Dash => package_dash(directory, renditions, segment_duration),
+    }
+}
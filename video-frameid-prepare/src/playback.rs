@@ -0,0 +1,130 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+use serde_json;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+use config::Config;
+use make_element;
+use manifest::{FrameRecord, Segment};
+
+/// One frame reaching the video sink
+#[derive(Debug, Clone, Copy)]
+pub struct Presentation {
+    /// Running time on the pipeline clock the frame was passed on to the
+    /// sink at, in nanoseconds
+    pub running_time: Option<u64>,
+    pub pts: Option<u64>,
+}
+
+/// Frames in the order the video sink got them, which is output order
+pub type PresentationLog = Arc<Mutex<Vec<Presentation>>>;
+
+#[derive(Serialize)]
+struct PresentedFrame {
+    id: String,
+    segment: Segment,
+    running_time: Option<u64>,
+    pts: Option<u64>,
+}
+
+/// Makes `sink` render every frame on time or, if late, anyway. Auto sinks
+/// are bins that may not have these properties, so they're set on the sink
+/// they pick too.
+fn render_every_frame(sink: &gst::Element) -> Result<(), Error> {
+    if sink.find_property("sync").is_some() {
+        sink.set_property("sync", &true)?;
+    }
+    if sink.find_property("qos").is_some() {
+        sink.set_property("qos", &false)?;
+    }
+    if sink.find_property("max-lateness").is_some() {
+        sink.set_property("max-lateness", &-1i64)?;
+    }
+
+    if let Ok(bin) = sink.clone().downcast::<gst::Bin>() {
+        bin.connect_element_added(|_, element| {
+            if let Err(e) = render_every_frame(element) {
+                eprintln!("Couldn't make {} render every frame: {}", element.get_name(), e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Plays the tagged sequence to the video sink described by `description`
+/// (e.g. "autovideosink" or "fakesink") and logs when each frame is shown
+pub fn setup_playback(pipeline: &gst::Pipeline, concat: &gst::Element, description: &str) -> Result<PresentationLog, Error> {
+    let queue = make_element("queue")?;
+    let videoconvert = make_element("videoconvert")?;
+    let clock_sync = make_element("identity")?;
+    let sink = gst::parse_launch(description)?;
+
+    // The log has to be the ground truth, so no frame is dropped and each
+    // is logged with the clock time it actually went out at, late or not,
+    // rather than the one it was scheduled for. identity waits on the clock
+    // like the sink does and hands the frame straight to it to render.
+    clock_sync.set_property("sync", &true)?;
+    render_every_frame(&sink)?;
+
+    pipeline.add_many(&[&queue, &videoconvert, &clock_sync, &sink])?;
+    gst::Element::link_many(&[concat, &queue, &videoconvert, &clock_sync, &sink])?;
+
+    let log: PresentationLog = Arc::new(Mutex::new(Vec::new()));
+    let log_clone = log.clone();
+    let element = clock_sync.clone();
+    clock_sync.get_static_pad("src").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            let running_time = element.get_clock()
+                .and_then(|clock| clock.get_time().nseconds())
+                .and_then(|now| element.get_base_time().nseconds().and_then(|base_time| now.checked_sub(base_time)));
+            log_clone.lock().unwrap().push(Presentation { running_time, pts: buffer.get_pts().nseconds() });
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(log)
+}
+
+/// Writes the presentation log next to the manifest, in the manifest's
+/// format (JSON if there's no manifest), returning its path
+pub fn write(config: &Config, frames: &[FrameRecord], log: &PresentationLog) -> Result<String, Error> {
+    let log = log.lock().unwrap();
+    if log.len() != frames.len() {
+        eprintln!("{} frames were tagged but {} reached the video sink", frames.len(), log.len());
+    }
+
+    let presented: Vec<PresentedFrame> = frames.iter()
+        .zip(log.iter())
+        .map(|(frame, presentation)| PresentedFrame {
+            id: frame.id(),
+            segment: frame.segment,
+            running_time: presentation.running_time,
+            pts: presentation.pts,
+        })
+        .collect();
+
+    let format = if config.manifest_format == "csv" { "csv" } else { "json" };
+    let path = format!("{}.presentation.{}", config.manifest_base(), format);
+    let mut writer = BufWriter::new(File::create(&path)?);
+
+    if format == "csv" {
+        let timestamp = |t: Option<u64>| t.map(|t| t.to_string()).unwrap_or_default();
+        writeln!(writer, "id,segment,running_time,pts")?;
+        for frame in &presented {
+            writeln!(writer, "\"{}\",{},{},{}", frame.id.replace('"', "\"\""), frame.segment.name(),
+                     timestamp(frame.running_time), timestamp(frame.pts))?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut writer, &presented)?;
+    }
+    writer.flush()?;
+
+    Ok(path)
+}
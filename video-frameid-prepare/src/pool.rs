@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use interrupt;
+
+/// Runs `work` on every job, `workers` at a time, and returns the results in
+/// the order of `jobs`. Once interrupted, the running jobs finish but the
+/// ones not started yet are skipped, their result being None.
+pub fn run<T, R, F>(jobs: Vec<T>, workers: usize, work: F) -> Vec<Option<R>>
+    where T: Send + 'static, R: Send + 'static, F: Fn(T) -> R + Send + Sync + 'static
+{
+    let total = jobs.len();
+    let queue: Arc<Mutex<VecDeque<(usize, T)>>> = Arc::new(Mutex::new(jobs.into_iter().enumerate().collect()));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let work = Arc::new(work);
+
+    let threads: Vec<_> = (0..workers.max(1).min(total))
+        .map(|_| {
+            let queue = queue.clone();
+            let results = results.clone();
+            let work = work.clone();
+            thread::spawn(move || loop {
+                if interrupt::is_interrupted() {
+                    break;
+                }
+                let (n, job) = match queue.lock().unwrap().pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+
+                let result = work(job);
+                results.lock().unwrap().push((n, result));
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut ordered: Vec<Option<R>> = (0..total).map(|_| None).collect();
+    for (n, result) in results.lock().unwrap().drain(..) {
+        ordered[n] = Some(result);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn results_are_in_job_order() {
+        // Earlier jobs take longer, so they finish last
+        let results = run((0..8u64).collect(), 3, |n| {
+            thread::sleep(Duration::from_millis((8 - n) * 5));
+            n * 2
+        });
+        assert_eq!(results, (0..8).map(|n| Some(n * 2)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn no_jobs_and_no_workers() {
+        assert!(run(Vec::<u32>::new(), 4, |n| n).is_empty());
+        assert_eq!(run(vec![1, 2], 0, |n| n + 1), vec![Some(2), Some(3)]);
+    }
+}
@@ -0,0 +1,188 @@
+use gst;
+use gst::prelude::*;
+
+use serde_json;
+
+use std::time::{Duration, Instant};
+
+use manifest::{FrameLog, Manifest};
+use ErrorMessage;
+
+pub const MODES: [&str; 3] = ["human", "json", "none"];
+
+const INTERVAL_SECONDS: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressMode {
+    Human,
+    /// One JSON object per line
+    Json,
+    None,
+}
+
+impl ProgressMode {
+    pub fn from_name(name: &str) -> Option<ProgressMode> {
+        match name {
+            "human" => Some(ProgressMode::Human),
+            "json" => Some(ProgressMode::Json),
+            "none" => Some(ProgressMode::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Status<'a> {
+    Progress {
+        input: &'a str,
+        /// Nanoseconds
+        position: Option<u64>,
+        duration: Option<u64>,
+        frames: u64,
+        total_frames: u64,
+        /// Frames tagged per second over the last interval
+        fps: f64,
+        /// Seconds
+        eta: Option<f64>,
+    },
+    Done {
+        input: &'a str,
+        frames: u64,
+        /// Seconds
+        elapsed: f64,
+    },
+    Error {
+        input: &'a str,
+        src: &'a str,
+        error: &'a str,
+        debug: Option<&'a str>,
+    },
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Reports on stderr how far a prepare pipeline got, as counted by the
+/// manifest logs
+pub struct Progress {
+    mode: ProgressMode,
+    input: String,
+    logs: Vec<FrameLog>,
+    total_frames: u64,
+    framerate: Option<gst::Fraction>,
+    start: Instant,
+    last: (Instant, u64),
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode, input: &str, manifest: &Manifest) -> Progress {
+        let now = Instant::now();
+
+        Progress {
+            mode,
+            input: input.to_owned(),
+            logs: manifest.logs(),
+            total_frames: manifest.expected_frames(),
+            framerate: manifest.framerate(),
+            start: now,
+            last: (now, 0),
+        }
+    }
+
+    pub fn mode(&self) -> ProgressMode {
+        self.mode
+    }
+
+    fn frames(&self) -> u64 {
+        self.logs.iter().map(|log| log.lock().unwrap().len() as u64).sum()
+    }
+
+    /// Reports the progress, unless the last report is less than an
+    /// interval old
+    pub fn update(&mut self, pipeline: &gst::Pipeline) {
+        let now = Instant::now();
+        let since_last = now.duration_since(self.last.0);
+        if self.mode == ProgressMode::None || since_last < Duration::from_secs(INTERVAL_SECONDS) {
+            return;
+        }
+
+        let frames = self.frames();
+        let fps = frames.saturating_sub(self.last.1) as f64 / seconds(since_last);
+        self.last = (now, frames);
+
+        // Estimated from the average rate so far, which is steadier than the
+        // last interval's
+        let elapsed = seconds(now.duration_since(self.start));
+        let eta = if frames > 0 {
+            Some(self.total_frames.saturating_sub(frames) as f64 * elapsed / frames as f64)
+        } else {
+            None
+        };
+
+        // concat doesn't know the length of the whole sequence, but the
+        // manifest does
+        let duration = self.framerate.map(|framerate| {
+            self.total_frames * 1_000_000_000 * *framerate.denom() as u64 / *framerate.numer() as u64
+        });
+
+        self.print(&Status::Progress {
+            input: &self.input,
+            position: pipeline.query_position::<gst::ClockTime>().and_then(|p| p.nseconds()),
+            duration,
+            frames,
+            total_frames: self.total_frames,
+            fps,
+            eta,
+        });
+    }
+
+    pub fn finish(&self) {
+        self.print(&Status::Done {
+            input: &self.input,
+            frames: self.frames(),
+            elapsed: seconds(self.start.elapsed()),
+        });
+    }
+
+    pub fn error(&self, err: &ErrorMessage) {
+        self.print(&Status::Error {
+            input: &self.input,
+            src: &err.src,
+            error: &err.error,
+            debug: err.debug.as_ref().map(String::as_ref),
+        });
+    }
+
+    fn print(&self, status: &Status) {
+        match self.mode {
+            ProgressMode::None => (),
+            ProgressMode::Json => eprintln!("{}", serde_json::to_string(status).unwrap()),
+            ProgressMode::Human => match *status {
+                Status::Progress { input, position, duration, frames, total_frames, fps, eta } => {
+                    let time = |t: Option<u64>| t.map(|t| format_time(t as f64 / 1e9)).unwrap_or_else(|| "--:--:--".to_owned());
+                    let percent = if total_frames > 0 { 100.0 * frames as f64 / total_frames as f64 } else { 0.0 };
+                    eprintln!("{}: {} / {} ({:.1}%), {}/{} frames tagged, {:.1} fps, ETA {}",
+                              input, time(position), time(duration), percent, frames, total_frames, fps,
+                              eta.map(format_time).unwrap_or_else(|| "--:--:--".to_owned()));
+                }
+                Status::Done { input, frames, elapsed } => {
+                    eprintln!("{}: done, {} frames tagged in {}", input, frames, format_time(elapsed));
+                }
+                Status::Error { input, src, error, debug } => {
+                    eprintln!("{}: error from {}: {}", input, src, error);
+                    if let Some(debug) = debug {
+                        eprintln!("{}:   debug: {}", input, debug);
+                    }
+                }
+            },
+        }
+    }
+}
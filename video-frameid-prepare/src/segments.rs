@@ -0,0 +1,276 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use config;
+use discover::{self, AudioFormat, VideoGeometry};
+use make_element;
+
+const CONTENT_KINDS: &str = "black, grey, bars, countdown, image:<PATH|URI> or clip:<PATH|URI>";
+
+/// What the pre-roll and post-roll segments show
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentContent {
+    Black,
+    Grey,
+    /// SMPTE colour bars
+    Bars,
+    /// Remaining seconds of the segment on black
+    Countdown,
+    /// Still image, repeated for the whole segment
+    Image(String),
+    /// Video clip, looped for the whole segment
+    Clip(String),
+}
+
+impl SegmentContent {
+    pub fn parse(value: &str) -> Result<SegmentContent, String> {
+        match value {
+            "black" => return Ok(SegmentContent::Black),
+            "grey" | "gray" => return Ok(SegmentContent::Grey),
+            "bars" => return Ok(SegmentContent::Bars),
+            "countdown" => return Ok(SegmentContent::Countdown),
+            _ => (),
+        }
+
+        if value.starts_with("image:") {
+            Ok(SegmentContent::Image(config::to_uri(&value["image:".len()..])?))
+        } else if value.starts_with("clip:") {
+            Ok(SegmentContent::Clip(config::to_uri(&value["clip:".len()..])?))
+        } else {
+            Err(format!("'{}' is not one of {}", value, CONTENT_KINDS))
+        }
+    }
+}
+
+/// Lets `samples` samples of `audio` through the pad, cutting short the
+/// buffer that goes past them and dropping any after it.
+pub fn limit_samples(pad: &gst::Pad, samples: u64, audio: &AudioFormat) {
+    let count = Mutex::new(0u64);
+    let bytes_per_sample = audio.bytes_per_sample();
+    let rate = audio.rate as u64;
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        let mut count = count.lock().unwrap();
+        let remaining = samples - *count;
+        if remaining == 0 {
+            return gst::PadProbeReturn::Drop;
+        }
+
+        let cut = match info.data {
+            Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                let buffer_samples = buffer.get_size() as u64 / bytes_per_sample;
+                if buffer_samples <= remaining {
+                    *count += buffer_samples;
+                    return gst::PadProbeReturn::Ok;
+                }
+                let mut cut = buffer.copy_region(gst::BufferCopyFlags::ALL, 0, Some((remaining * bytes_per_sample) as usize)).unwrap();
+                cut.get_mut().unwrap().set_duration(gst::ClockTime::from_nseconds(remaining * 1_000_000_000 / rate));
+                cut
+            }
+            _ => return gst::PadProbeReturn::Ok,
+        };
+        *count = samples;
+        info.data = Some(gst::PadProbeData::Buffer(cut));
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Lets `frames` buffers through the pad and then ends the stream, for
+/// sources that can't stop by themselves after a number of frames.
+pub fn limit_frames(pad: &gst::Pad, frames: u64) {
+    let count = Arc::new(Mutex::new(0u64));
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, _info| {
+        let mut count = count.lock().unwrap();
+        *count += 1;
+
+        if *count <= frames {
+            return gst::PadProbeReturn::Ok;
+        }
+        if *count == frames + 1 {
+            pad.push_event(gst::Event::new_eos().build());
+        }
+        gst::PadProbeReturn::Drop
+    });
+}
+
+fn setup_test_source(pipeline: &gst::Pipeline, properties: &str, frames: u64) -> Result<gst::Element, Error> {
+    let src = gst::parse_launch(&format!("videotestsrc {} num-buffers={}", properties, frames))?;
+    pipeline.add(&src)?;
+    Ok(src)
+}
+
+fn setup_countdown_source(pipeline: &gst::Pipeline, geometry: &VideoGeometry, frames: u64) -> Result<gst::Element, Error> {
+    let src = gst::parse_launch(&format!("videotestsrc pattern=black num-buffers={}", frames))?;
+    let overlay = gst::parse_launch("textoverlay font-desc=\"Sans Bold 72\" valignment=center halignment=center")?;
+
+    pipeline.add_many(&[&src, &overlay])?;
+    src.link(&overlay)?;
+
+    let framerate = geometry.framerate;
+    let overlay_clone = overlay.clone();
+    let index = Arc::new(Mutex::new(0u64));
+    src.get_static_pad("src").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+        let mut index = index.lock().unwrap();
+        let remaining = (frames - *index) as i64 * *framerate.denom() as i64;
+        let seconds = (remaining + *framerate.numer() as i64 - 1) / *framerate.numer() as i64;
+        overlay_clone.set_property("text", &seconds.to_string()).unwrap();
+        *index += 1;
+
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(overlay)
+}
+
+fn setup_image_source(pipeline: &gst::Pipeline, uri: &str, geometry: &VideoGeometry, frames: u64) -> Result<gst::Element, Error> {
+    let uridec = make_element("uridecodebin")?;
+    let imagefreeze = make_element("imagefreeze")?;
+    let capsfilter = make_element("capsfilter")?;
+
+    uridec.set_property("uri", &uri)?;
+    imagefreeze.set_property("num-buffers", &(frames as i32))?;
+    // imagefreeze picks its framerate from downstream, so pin it here
+    capsfilter.set_property("caps", &gst::Caps::new_simple("video/x-raw", &[("framerate", &geometry.framerate)]))?;
+
+    pipeline.add_many(&[&uridec, &imagefreeze, &capsfilter])?;
+    imagefreeze.link(&capsfilter)?;
+
+    let imagefreeze_clone = imagefreeze.clone();
+    uridec.connect_pad_added(move |_, src_pad| {
+        let sink_pad = imagefreeze_clone.get_static_pad("sink").unwrap();
+        if sink_pad.is_linked() {
+            return;
+        }
+        let _ = src_pad.link(&sink_pad);
+    });
+
+    Ok(capsfilter)
+}
+
+/// Where the current pass of a looped clip got to
+struct Loop {
+    segment: Option<gst::FormattedSegment<gst::ClockTime>>,
+    /// Running time the pass ends at, before the offset
+    pass_end: u64,
+    /// Sum of the lengths of the previous passes
+    offset: u64,
+    seeking: bool,
+}
+
+/// Seeks the stream of `pad` back to its start whenever it ends, until it
+/// lasted `duration` nanoseconds. Each pass is offset to follow the
+/// previous one, and the flushes of the seeks aren't let downstream, so it
+/// looks like one long stream from there. Buffers without a duration are
+/// taken to last `frame_duration`.
+fn loop_stream(pad: &gst::Pad, duration: u64, frame_duration: u64) {
+    let state = Mutex::new(Loop { segment: None, pass_end: 0, offset: 0, seeking: false });
+
+    let probe_types = gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM | gst::PadProbeType::EVENT_FLUSH;
+    pad.add_probe(probe_types, move |pad, info| {
+        let mut state = state.lock().unwrap();
+        match info.data {
+            Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                let start = state.segment.as_ref().and_then(|segment| segment.to_running_time(buffer.get_pts()).nseconds());
+                if let Some(start) = start {
+                    let end = start + buffer.get_duration().nseconds().unwrap_or(frame_duration);
+                    state.pass_end = state.pass_end.max(end);
+                }
+            }
+            Some(gst::PadProbeData::Event(ref event)) => match event.view() {
+                gst::EventView::Segment(e) => {
+                    state.segment = e.get_segment().downcast_ref::<gst::ClockTime>().cloned();
+                }
+                // A pass without frames would loop forever
+                gst::EventView::Eos(..) if state.pass_end > 0 && state.offset + state.pass_end < duration => {
+                    state.offset += state.pass_end;
+                    state.pass_end = 0;
+                    state.seeking = true;
+                    pad.set_offset(state.offset as i64);
+
+                    // Seeking from the streaming thread would wait for itself
+                    let pad = pad.clone();
+                    thread::spawn(move || {
+                        let seek = gst::Event::new_seek(
+                            1.0,
+                            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+                            gst::SeekType::Set,
+                            gst::ClockTime::from_nseconds(0),
+                            gst::SeekType::None,
+                            gst::ClockTime::none(),
+                        ).build();
+                        if !pad.send_event(seek) {
+                            eprintln!("Failed to loop the clip back to its start");
+                        }
+                    });
+                    return gst::PadProbeReturn::Drop;
+                }
+                gst::EventView::FlushStart(..) if state.seeking => return gst::PadProbeReturn::Drop,
+                gst::EventView::FlushStop(..) if state.seeking => {
+                    state.seeking = false;
+                    return gst::PadProbeReturn::Drop;
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Plays the clip at `uri` as many times as the segment needs, from a
+/// single decoder. The frame limit on the branch cuts the last pass short.
+fn setup_clip_source(pipeline: &gst::Pipeline, uri: &str, geometry: &VideoGeometry, frames: u64) -> Result<gst::Element, Error> {
+    let clip = discover::discover(uri)?;
+    if clip.frames(geometry.framerate) == 0 {
+        Err(discover::EmptyInput(uri.to_owned()))?;
+    }
+
+    let uridec = make_element("uridecodebin")?;
+    let identity = make_element("identity")?;
+    uridec.set_property("uri", &uri)?;
+    pipeline.add_many(&[&uridec, &identity])?;
+
+    let frame_duration = |framerate: gst::Fraction| {
+        if *framerate.numer() > 0 {
+            1_000_000_000 * *framerate.denom() as u64 / *framerate.numer() as u64
+        } else {
+            0
+        }
+    };
+    let duration = frames * frame_duration(geometry.framerate);
+    let clip_frame_duration = frame_duration(clip.video.framerate);
+    let sink_pad = identity.get_static_pad("sink").unwrap();
+    uridec.connect_pad_added(move |_, src_pad| {
+        let caps = src_pad.get_current_caps().unwrap();
+        if !caps.get_structure(0).unwrap().get_name().starts_with("video/") || sink_pad.is_linked() {
+            return;
+        }
+        loop_stream(src_pad, duration, clip_frame_duration);
+        let _ = src_pad.link(&sink_pad);
+    });
+
+    Ok(identity)
+}
+
+/// Adds the elements producing `frames` frames of `content` and returns the
+/// element whose src pad outputs them. The frames may still need converting
+/// to the input's format, size and framerate.
+pub fn setup_source(pipeline: &gst::Pipeline, content: &SegmentContent, geometry: &VideoGeometry, frames: u64) -> Result<gst::Element, Error> {
+    match *content {
+        SegmentContent::Black => setup_test_source(pipeline, "pattern=black", frames),
+        SegmentContent::Grey => setup_test_source(pipeline, "pattern=solid-color foreground-color=0xff808080", frames),
+        SegmentContent::Bars => setup_test_source(pipeline, "pattern=smpte", frames),
+        SegmentContent::Countdown => setup_countdown_source(pipeline, geometry, frames),
+        SegmentContent::Image(ref uri) => setup_image_source(pipeline, uri, geometry, frames),
+        SegmentContent::Clip(ref uri) => setup_clip_source(pipeline, uri, geometry, frames),
+    }
+}
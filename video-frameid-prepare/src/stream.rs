@@ -0,0 +1,419 @@
+use gst;
+use gst::prelude::*;
+use gst_rtsp_server;
+use gst_rtsp_server::prelude::*;
+use glib;
+
+use failure::Error;
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use encoding::{EncodingSettings, Profile};
+use make_element;
+
+pub const KINDS: [&str; 4] = ["rtp", "rtsp", "srt", "hls"];
+
+const RTP_PAYLOAD_TYPE: u32 = 96;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Profile {} can't be streamed over {}", _0, _1)]
+struct UnsupportedStream(&'static str, &'static str);
+
+/// How the prepared content is sent live instead of written to a file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    /// RTP over UDP to udp://HOST:PORT
+    Rtp,
+    /// RTSP server at rtsp://HOST:PORT/PATH
+    Rtsp,
+    /// MPEG-TS over SRT, listening at srt://HOST:PORT
+    Srt,
+    /// HLS playlist and segments written to a directory and served over HTTP
+    Hls,
+}
+
+impl StreamKind {
+    pub fn from_name(name: &str) -> Option<StreamKind> {
+        match name {
+            "rtp" => Some(StreamKind::Rtp),
+            "rtsp" => Some(StreamKind::Rtsp),
+            "srt" => Some(StreamKind::Srt),
+            "hls" => Some(StreamKind::Hls),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StreamKind::Rtp => "rtp",
+            StreamKind::Rtsp => "rtsp",
+            StreamKind::Srt => "srt",
+            StreamKind::Hls => "hls",
+        }
+    }
+
+    /// RTP and RTSP only carry the video
+    pub fn has_audio(&self) -> bool {
+        match *self {
+            StreamKind::Rtp | StreamKind::Rtsp => false,
+            StreamKind::Srt | StreamKind::Hls => true,
+        }
+    }
+
+    /// Checks `destination` has the form the kind expects, and stays on
+    /// this machine
+    pub fn check_destination(&self, destination: &str) -> Result<(), String> {
+        let schemes: &[&str] = match *self {
+            StreamKind::Rtp => &["udp", "rtp"],
+            StreamKind::Rtsp => &["rtsp"],
+            StreamKind::Srt => &["srt"],
+            StreamKind::Hls => return Ok(()),
+        };
+
+        let address = Address::parse(destination)?;
+        if !schemes.contains(&address.scheme.as_str()) {
+            return Err(format!("{} streams need a {}:// destination, not {}", self.name(), schemes[0], destination));
+        }
+        if !address.is_loopback() {
+            return Err(format!("{} is not a loopback address, streams stay on this machine", address.host));
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of a scheme://host:port/path?query destination
+struct Address {
+    scheme: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Address {
+    fn parse(destination: &str) -> Result<Address, String> {
+        let invalid = || format!("'{}' is not a destination like udp://127.0.0.1:5004", destination);
+
+        let scheme_end = destination.find("://").ok_or_else(invalid)?;
+        let rest = &destination[scheme_end + 3..];
+        let authority_end = rest.find(|c| c == '/' || c == '?').unwrap_or_else(|| rest.len());
+        let (authority, path) = rest.split_at(authority_end);
+        // The query is for the element handling the destination (e.g. srtsink)
+        let path = path.split('?').next().unwrap();
+
+        let port_start = authority.rfind(':').ok_or_else(invalid)?;
+        let host = authority[..port_start].trim_matches(|c| c == '[' || c == ']');
+        let port = authority[port_start + 1..].parse::<u16>().map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Address {
+            scheme: destination[..scheme_end].to_owned(),
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    fn is_loopback(&self) -> bool {
+        self.host == "localhost" || self.host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+    }
+}
+
+/// Keeps whatever serves the stream running until it's dropped
+pub struct StreamServer {
+    rtsp: Option<(gst_rtsp_server::RTSPServer, glib::SourceId, glib::MainLoop)>,
+    /// The udpsink feeding the RTSP relay, and the port the relay gets
+    /// bound to once a client asked for the stream
+    relay: Option<(gst::Element, mpsc::Receiver<u16>)>,
+}
+
+impl StreamServer {
+    fn none() -> StreamServer {
+        StreamServer { rtsp: None, relay: None }
+    }
+
+    /// A shared RTSP stream would start before anyone watches it, so the
+    /// pipeline only starts once the first client asked for it and the
+    /// relay is listening
+    pub fn wait_for_client(&self) -> Result<(), Error> {
+        if let Some((ref server, ..)) = self.rtsp {
+            println!("Waiting for a client on port {}", server.get_bound_port());
+        }
+        if let Some((ref sink, ref relay_port)) = self.relay {
+            sink.set_property("port", &(relay_port.recv()? as i32))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        if let Some((_, id, ref main_loop)) = self.rtsp.take() {
+            glib::source_remove(id);
+            main_loop.quit();
+        }
+    }
+}
+
+fn payloader(settings: &EncodingSettings, kind: StreamKind) -> Result<(gst::Element, &'static str), Error> {
+    let (factory, encoding_name) = match settings.profile {
+        Profile::H264 | Profile::H264Lossless => ("rtph264pay", "H264"),
+        Profile::Vp9 => ("rtpvp9pay", "VP9"),
+        profile => Err(UnsupportedStream(profile.name(), kind.name()))?,
+    };
+    let pay = make_element(factory)?;
+    pay.set_property("pt", &RTP_PAYLOAD_TYPE)?;
+    if factory == "rtph264pay" {
+        // Receivers joining late need the parameter sets on every keyframe
+        pay.set_property("config-interval", &-1i32)?;
+    }
+
+    Ok((pay, encoding_name))
+}
+
+/// MPEG-TS based streams only carry H.264 here
+fn h264parse(settings: &EncodingSettings, kind: StreamKind) -> Result<gst::Element, Error> {
+    match settings.profile {
+        Profile::H264 | Profile::H264Lossless => Ok(make_element("h264parse")?),
+        profile => Err(UnsupportedStream(profile.name(), kind.name()))?,
+    }
+}
+
+fn setup_rtp(pipeline: &gst::Pipeline, settings: &EncodingSettings, video: &gst::Element, host: &str, port: u16) -> Result<(gst::Element, &'static str), Error> {
+    let (pay, encoding_name) = payloader(settings, StreamKind::Rtp)?;
+    let sink = make_element("udpsink")?;
+
+    sink.set_property("host", &host)?;
+    sink.set_property("port", &(port as i32))?;
+
+    pipeline.add_many(&[&pay, &sink])?;
+    gst::Element::link_many(&[video, &pay, &sink])?;
+
+    Ok((sink, encoding_name))
+}
+
+/// The pipeline sends RTP to a local port and the RTSP server relays it, as
+/// the server can't take over an already built pipeline. The relay binds any
+/// free port when the first client asks for the stream, and the pipeline is
+/// pointed at it before it starts.
+fn setup_rtsp(pipeline: &gst::Pipeline, settings: &EncodingSettings, video: &gst::Element, address: &Address) -> Result<StreamServer, Error> {
+    let (sink, encoding_name) = setup_rtp(pipeline, settings, video, "127.0.0.1", 0)?;
+
+    let server = gst_rtsp_server::RTSPServer::new();
+    server.set_address(&address.host);
+    server.set_service(&address.port.to_string());
+
+    let factory = gst_rtsp_server::RTSPMediaFactory::new();
+    factory.set_launch(&format!(
+        "( udpsrc address=127.0.0.1 port=0 caps=\"application/x-rtp, media=video, clock-rate=90000, encoding-name={}, payload={}\" name=pay0 )",
+        encoding_name, RTP_PAYLOAD_TYPE));
+    factory.set_shared(true);
+    let path = if address.path.is_empty() { "/" } else { &address.path };
+    server.get_mount_points()
+        .ok_or_else(|| format_err!("RTSP server without mount points"))?
+        .add_factory(path, &factory);
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    factory.connect_media_configure(move |_, media| {
+        let relay = media.get_element().unwrap()
+            .dynamic_cast::<gst::Bin>().unwrap()
+            .get_by_name("pay0").unwrap();
+        // udpsrc updates its port once it's bound
+        let sender = Mutex::new(sender.lock().unwrap().clone());
+        relay.connect_notify(Some("port"), move |relay, _| {
+            let port = relay.get_property("port").unwrap().get::<i32>().unwrap();
+            let _ = sender.lock().unwrap().send(port as u16);
+        });
+    });
+
+    // The server runs on the default main context, which nothing else
+    // iterates
+    let main_loop = glib::MainLoop::new(None, false);
+    let id = server.attach(None);
+    let main_loop_clone = main_loop.clone();
+    thread::spawn(move || main_loop_clone.run());
+
+    Ok(StreamServer {
+        rtsp: Some((server, id, main_loop)),
+        relay: Some((sink, receiver)),
+    })
+}
+
+fn setup_srt(pipeline: &gst::Pipeline, settings: &EncodingSettings, video: &gst::Element, audio: Option<&gst::Element>, destination: &str) -> Result<(), Error> {
+    let parse = h264parse(settings, StreamKind::Srt)?;
+    let mux = make_element("mpegtsmux")?;
+    let sink = make_element("srtsink")?;
+
+    // The system under test connects to us
+    let uri = if destination.contains("mode=") {
+        destination.to_owned()
+    } else if destination.contains('?') {
+        format!("{}&mode=listener", destination)
+    } else {
+        format!("{}?mode=listener", destination)
+    };
+    sink.set_property("uri", &uri)?;
+
+    pipeline.add_many(&[&parse, &mux, &sink])?;
+    gst::Element::link_many(&[video, &parse, &mux, &sink])?;
+    if let Some(audio) = audio {
+        audio.link(&mux)?;
+    }
+
+    Ok(())
+}
+
+fn setup_hls(pipeline: &gst::Pipeline, settings: &EncodingSettings, video: &gst::Element, audio: Option<&gst::Element>, directory: &str, port: u16) -> Result<(), Error> {
+    fs::create_dir_all(directory)?;
+    let directory = Path::new(directory);
+
+    let parse = h264parse(settings, StreamKind::Hls)?;
+    let sink = make_element("hlssink2")?;
+
+    sink.set_property("location", &directory.join("segment%05d.ts").to_string_lossy().into_owned())?;
+    sink.set_property("playlist-location", &directory.join("playlist.m3u8").to_string_lossy().into_owned())?;
+    // Keep every segment, so a late client can still get the pre-roll
+    sink.set_property("max-files", &0u32)?;
+    sink.set_property("playlist-length", &0u32)?;
+
+    pipeline.add_many(&[&parse, &sink])?;
+    gst::Element::link_many(&[video, &parse])?;
+    assert_eq!(parse.get_static_pad("src").unwrap().link(&sink.get_request_pad("video").unwrap()),
+               gst::PadLinkReturn::Ok);
+    if let Some(audio) = audio {
+        assert_eq!(audio.get_static_pad("src").unwrap().link(&sink.get_request_pad("audio").unwrap()),
+                   gst::PadLinkReturn::Ok);
+    }
+
+    serve_directory(directory.to_path_buf(), port)?;
+    println!("Serving http://127.0.0.1:{}/playlist.m3u8", port);
+
+    Ok(())
+}
+
+/// Links the encoded video (and audio, if the kind carries it) to a live
+/// output
+pub fn setup_stream(pipeline: &gst::Pipeline, kind: StreamKind, destination: &str, hls_port: u16, settings: &EncodingSettings,
+                    video: &gst::Element, audio: Option<&gst::Element>) -> Result<StreamServer, Error> {
+    match kind {
+        StreamKind::Rtp => {
+            let address = Address::parse(destination).map_err(|e| format_err!("{}", e))?;
+            setup_rtp(pipeline, settings, video, &address.host, address.port)?;
+        }
+        StreamKind::Rtsp => {
+            let address = Address::parse(destination).map_err(|e| format_err!("{}", e))?;
+            return setup_rtsp(pipeline, settings, video, &address);
+        }
+        StreamKind::Srt => setup_srt(pipeline, settings, video, audio, destination)?,
+        StreamKind::Hls => setup_hls(pipeline, settings, video, audio, destination, hls_port)?,
+    }
+
+    Ok(StreamServer::none())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("ts") => "video/mp2t",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+fn respond(mut stream: TcpStream, directory: &Path) -> Result<(), Error> {
+    let mut request = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut request)?;
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or("/"));
+    let relative = Path::new(target.split('?').next().unwrap().trim_left_matches('/'));
+
+    // Only plain file names below the directory are served
+    let safe = relative.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    let path = directory.join(relative);
+    if method != "GET" || !safe || !path.is_file() {
+        write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let mut body = Vec::new();
+    File::open(&path)?.read_to_end(&mut body)?;
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+           content_type(&path), body.len())?;
+    stream.write_all(&body)?;
+
+    Ok(())
+}
+
+/// Minimal HTTP server for the HLS files, only reachable from this machine
+fn serve_directory(directory: PathBuf, port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let directory = directory.clone();
+                thread::spawn(move || {
+                    if let Err(e) = respond(stream, &directory) {
+                        eprintln!("Failed to serve HLS request: {}", e);
+                    }
+                });
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_parts() {
+        let address = Address::parse("rtsp://127.0.0.1:8554/test").unwrap();
+        assert_eq!((address.scheme.as_str(), address.host.as_str(), address.port, address.path.as_str()),
+                   ("rtsp", "127.0.0.1", 8554, "/test"));
+
+        // The mount path leaves out the query
+        assert_eq!(Address::parse("rtsp://127.0.0.1:8554/test?latency=200").unwrap().path, "/test");
+        assert_eq!(Address::parse("srt://127.0.0.1:9000?mode=caller").unwrap().path, "");
+
+        // IPv6 hosts are bracketed because of the port
+        let address = Address::parse("udp://[::1]:5004").unwrap();
+        assert_eq!((address.host.as_str(), address.port), ("::1", 5004));
+        assert!(address.is_loopback());
+    }
+
+    #[test]
+    fn address_needs_a_port() {
+        assert_eq!(Address::parse("udp://127.0.0.1:65535").unwrap().port, 65535);
+        assert!(Address::parse("udp://127.0.0.1:65536").is_err());
+        assert_eq!(Address::parse("udp://127.0.0.1").err(),
+                   Some("'udp://127.0.0.1' is not a destination like udp://127.0.0.1:5004".to_owned()));
+    }
+
+    #[test]
+    fn destinations_need_the_kind_scheme_and_loopback() {
+        assert_eq!(StreamKind::Rtp.check_destination("rtp://localhost:5004"), Ok(()));
+        assert_eq!(StreamKind::Srt.check_destination("udp://127.0.0.1:5004"),
+                   Err("srt streams need a srt:// destination, not udp://127.0.0.1:5004".to_owned()));
+        assert_eq!(StreamKind::Rtp.check_destination("udp://192.168.1.2:5004"),
+                   Err("192.168.1.2 is not a loopback address, streams stay on this machine".to_owned()));
+        // HLS is written to a directory
+        assert_eq!(StreamKind::Hls.check_destination("out/hls"), Ok(()));
+    }
+}
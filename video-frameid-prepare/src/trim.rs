@@ -0,0 +1,260 @@
+use gst;
+use gst::prelude::*;
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Fail)]
+#[fail(display = "The trimmed range of {} has no frames", _0)]
+pub struct EmptyRange(pub String);
+
+/// Start or end of the prepared range of the input
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Frame(u64),
+    /// Nanoseconds
+    Time(u64),
+}
+
+impl Position {
+    /// Accepts a frame number, seconds with an 's' suffix or [HH:]MM:SS[.fff]
+    pub fn parse(value: &str) -> Result<Position, String> {
+        let invalid = || format!("'{}' is not a frame number, seconds (e.g. 12.5s) or a timestamp (e.g. 01:02:03.500)", value);
+
+        if value.ends_with('s') {
+            let seconds = value[..value.len() - 1].parse::<f64>().map_err(|_| invalid())?;
+            if seconds < 0.0 {
+                return Err(invalid());
+            }
+            return Ok(Position::Time((seconds * 1_000_000_000f64).round() as u64));
+        }
+
+        if value.contains(':') {
+            let mut seconds = 0f64;
+            for part in value.split(':') {
+                let part = part.parse::<f64>().map_err(|_| invalid())?;
+                if part < 0.0 {
+                    return Err(invalid());
+                }
+                seconds = seconds * 60.0 + part;
+            }
+            return Ok(Position::Time((seconds * 1_000_000_000f64).round() as u64));
+        }
+
+        value.parse::<u64>().map(Position::Frame).map_err(|_| invalid())
+    }
+
+    pub fn time(&self, framerate: gst::Fraction) -> gst::ClockTime {
+        match *self {
+            Position::Frame(frame) => gst::ClockTime::from_nseconds(
+                frame * 1_000_000_000 * *framerate.denom() as u64 / *framerate.numer() as u64),
+            Position::Time(nseconds) => gst::ClockTime::from_nseconds(nseconds),
+        }
+    }
+
+    /// Number of the frame shown at this position
+    pub fn frame(&self, framerate: gst::Fraction) -> u64 {
+        match *self {
+            Position::Frame(frame) => frame,
+            // Tolerate timestamps rounded to the nanosecond
+            Position::Time(nseconds) => (nseconds + 1) * *framerate.numer() as u64
+                / (1_000_000_000 * *framerate.denom() as u64),
+        }
+    }
+}
+
+/// How frames of a trimmed input are numbered
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Numbering {
+    /// The first prepared frame is 0
+    Restart,
+    /// Frames keep their number in the source
+    Source,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Trim {
+    pub start: Option<Position>,
+    /// Exclusive
+    pub end: Option<Position>,
+    pub numbering: Numbering,
+}
+
+impl Trim {
+    pub fn is_set(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+
+    /// Index rsframeid writes on the first prepared frame
+    pub fn first_index(&self, framerate: gst::Fraction) -> u64 {
+        match (self.numbering, self.start) {
+            (Numbering::Source, Some(start)) => start.frame(framerate),
+            _ => 0,
+        }
+    }
+
+    /// Rejects an end that isn't after the start, when both are in the same
+    /// unit. Other ranges are checked once the framerate is known.
+    pub fn check(&self) -> Result<(), String> {
+        let empty = match (self.start, self.end) {
+            (Some(Position::Frame(start)), Some(Position::Frame(end))) => end <= start,
+            (Some(Position::Time(start)), Some(Position::Time(end))) => end <= start,
+            _ => false,
+        };
+        if empty {
+            Err("--end has to be after --start".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of frames left of an input lasting `frames`
+    pub fn frames(&self, frames: u64, framerate: gst::Fraction) -> u64 {
+        let end = self.end.map(|end| end.frame(framerate).min(frames)).unwrap_or(frames);
+        let start = self.start.map(|start| start.frame(framerate)).unwrap_or(0);
+        end.saturating_sub(start)
+    }
+
+    fn seek_event(&self, framerate: gst::Fraction) -> gst::Event {
+        let start = self.start.map(|p| p.time(framerate)).unwrap_or_else(|| gst::ClockTime::from_nseconds(0));
+        let (stop_type, stop) = match self.end {
+            Some(end) => (gst::SeekType::Set, end.time(framerate)),
+            None => (gst::SeekType::None, gst::ClockTime::none()),
+        };
+
+        gst::Event::new_seek(
+            1.0,
+            gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+            gst::SeekType::Set,
+            start,
+            stop_type,
+            stop,
+        ).build()
+    }
+}
+
+/// Where the seek of a trimmed branch got to
+enum SeekState {
+    Pending(gst::Event),
+    Seeking,
+    Done,
+}
+
+impl SeekState {
+    fn is_seeking(&self) -> bool {
+        match *self {
+            SeekState::Seeking => true,
+            _ => false,
+        }
+    }
+}
+
+/// Seeks a decoder branch to the trimmed range from its first frame. The
+/// frames decoded before the seek and the flushes of the seek aren't let
+/// downstream, so the branch starts with the first frame of the range
+/// without having to wait for the pipeline to preroll.
+#[derive(Clone)]
+pub struct PendingSeek {
+    state: Arc<Mutex<SeekState>>,
+}
+
+impl PendingSeek {
+    pub fn new(trim: &Trim, framerate: gst::Fraction) -> PendingSeek {
+        PendingSeek {
+            state: Arc::new(Mutex::new(SeekState::Pending(trim.seek_event(framerate)))),
+        }
+    }
+
+    /// Seeks from `pad`, a sink pad linked to the decoder
+    pub fn seek_from(&self, pad: &gst::Pad) {
+        let state = self.state.clone();
+
+        let probe_types = gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM | gst::PadProbeType::EVENT_FLUSH;
+        pad.add_probe(probe_types, move |pad, info| {
+            let mut state = state.lock().unwrap();
+            match info.data {
+                Some(gst::PadProbeData::Buffer(..)) => match mem::replace(&mut *state, SeekState::Seeking) {
+                    SeekState::Pending(seek) => {
+                        // Seeking from the streaming thread would wait for itself
+                        let pad = pad.clone();
+                        thread::spawn(move || {
+                            if !pad.send_event(seek) {
+                                eprintln!("Failed to seek to the trimmed range");
+                            }
+                        });
+                        return gst::PadProbeReturn::Drop;
+                    }
+                    SeekState::Seeking => return gst::PadProbeReturn::Drop,
+                    SeekState::Done => {
+                        *state = SeekState::Done;
+                        return gst::PadProbeReturn::Remove;
+                    }
+                },
+                Some(gst::PadProbeData::Event(ref event)) => match event.view() {
+                    gst::EventView::FlushStart(..) if state.is_seeking() => return gst::PadProbeReturn::Drop,
+                    gst::EventView::FlushStop(..) if state.is_seeking() => {
+                        *state = SeekState::Done;
+                        return gst::PadProbeReturn::Drop;
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nseconds(value: &str) -> u64 {
+        match Position::parse(value).unwrap() {
+            Position::Time(nseconds) => nseconds,
+            Position::Frame(frame) => panic!("{} parsed as frame {}", value, frame),
+        }
+    }
+
+    #[test]
+    fn plain_numbers_are_frames() {
+        match Position::parse("120").unwrap() {
+            Position::Frame(frame) => assert_eq!(frame, 120),
+            Position::Time(nseconds) => panic!("parsed as {} ns", nseconds),
+        }
+        assert_eq!(Position::parse("1.5").unwrap_err(),
+                   "'1.5' is not a frame number, seconds (e.g. 12.5s) or a timestamp (e.g. 01:02:03.500)");
+    }
+
+    #[test]
+    fn seconds_and_timestamps() {
+        assert_eq!(nseconds("12.5s"), 12_500_000_000);
+        assert_eq!(nseconds("01:30"), 90_000_000_000);
+        assert_eq!(nseconds("01:02:03.500"), 3_723_500_000_000);
+        assert!(Position::parse("-1s").is_err());
+        assert!(Position::parse("01:-30").is_err());
+    }
+
+    #[test]
+    fn times_round_to_the_frame_they_show() {
+        let framerate = gst::Fraction::new(25, 1);
+        // 40 ms frames, with the timestamp of frame 1 rounded down
+        assert_eq!(Position::Time(39_999_999).frame(framerate), 1);
+        assert_eq!(Position::Time(39_999_998).frame(framerate), 0);
+        assert_eq!(Position::Frame(3).time(framerate), gst::ClockTime::from_mseconds(120));
+    }
+
+    #[test]
+    fn end_has_to_be_after_start() {
+        let trim = |start, end| Trim { start: Some(start), end: Some(end), numbering: Numbering::Restart };
+        assert_eq!(trim(Position::Frame(10), Position::Frame(10)).check().unwrap_err(), "--end has to be after --start");
+        assert!(trim(Position::Frame(10), Position::Frame(11)).check().is_ok());
+        assert!(trim(Position::Time(2_000_000_000), Position::Time(1_000_000_000)).check().is_err());
+        // Needs the framerate, so it's left to the pipeline
+        assert!(trim(Position::Frame(100), Position::Time(1_000_000_000)).check().is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use config::Config;
+use interrupt::{self, Interrupted};
+use main_loop;
+use make_element;
+use manifest::FrameRecord;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Verification of {} failed: {} problem(s)", _0, _1)]
+pub struct VerificationFailed(String, usize);
+
+/// What rsframeidfilter read from each decoded frame, in decoding order
+type DecodedIds = Arc<Mutex<Vec<Option<String>>>>;
+
+fn decode_ids(config: &Config) -> Result<Vec<Option<String>>, Error> {
+    let pipeline = gst::Pipeline::new(None);
+    let filesrc = make_element("filesrc")?;
+    let decodebin = make_element("decodebin")?;
+    let videoconvert = make_element("videoconvert")?;
+    let filter = make_element("rsframeidfilter")?;
+    let sink = make_element("fakesink")?;
+
+    filesrc.set_property("location", &config.output)?;
+    filter.set_property("position", &config.position)?;
+
+    pipeline.add_many(&[&filesrc, &decodebin, &videoconvert, &filter, &sink])?;
+    filesrc.link(&decodebin)?;
+    gst::Element::link_many(&[&videoconvert, &filter, &sink])?;
+
+    let videoconvert_clone = videoconvert.clone();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let sink_pad = videoconvert_clone.get_static_pad("sink").unwrap();
+        let caps = src_pad.get_current_caps().unwrap();
+        if caps.get_structure(0).unwrap().get_name().starts_with("video/") && !sink_pad.is_linked() {
+            let _ = src_pad.link(&sink_pad);
+        }
+    });
+
+    // Every frame entering the filter gets a slot, which the filter's
+    // message fills in while it's still processing that frame
+    let decoded: DecodedIds = Arc::new(Mutex::new(Vec::new()));
+    let decoded_clone = decoded.clone();
+    filter.get_static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, _info| {
+        decoded_clone.lock().unwrap().push(None);
+        gst::PadProbeReturn::Ok
+    });
+
+    let decoded_clone = decoded.clone();
+    pipeline.get_bus().unwrap().set_sync_handler(move |_bus, msg| {
+        if let gst::MessageView::Element(element) = msg.view() {
+            let s = element.get_structure().unwrap();
+            if s.get_name() == "frameid-found" {
+                if let Some(slot) = decoded_clone.lock().unwrap().last_mut() {
+                    *slot = s.get::<String>("frameid");
+                }
+            }
+        }
+        gst::BusSyncReply::Pass
+    });
+
+    main_loop(pipeline, None)?;
+    if interrupt::is_interrupted() {
+        Err(Interrupted)?;
+    }
+
+    let decoded = decoded.lock().unwrap().clone();
+    Ok(decoded)
+}
+
+/// Compares the ids read back from the output with the ones the manifest
+/// says were written, returning a description of every problem found
+fn compare(expected: &[FrameRecord], decoded: &[Option<String>]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let positions: HashMap<String, usize> = expected.iter()
+        .enumerate()
+        .map(|(position, frame)| (frame.id(), position))
+        .collect();
+
+    if decoded.len() != expected.len() {
+        problems.push(format!("Expected {} frames but decoded {}", expected.len(), decoded.len()));
+    }
+
+    let mut next = 0;
+    for (n, id) in decoded.iter().enumerate() {
+        let id = match *id {
+            Some(ref id) => id,
+            None => {
+                let expected_id = expected.get(n).map(FrameRecord::id).unwrap_or_else(|| "-".to_owned());
+                problems.push(format!("Frame {} is unreadable (expected {})", n, expected_id));
+                continue;
+            }
+        };
+
+        match positions.get(id) {
+            None => problems.push(format!("Frame {} has unknown id {}", n, id)),
+            Some(&position) if position < next => {
+                problems.push(format!("Frame {} has id {} out of order or repeated", n, id));
+            }
+            Some(&position) => {
+                if position > next {
+                    problems.push(format!("Ids {} to {} are missing before frame {}",
+                                          expected[next].id(), expected[position - 1].id(), n));
+                }
+                next = position + 1;
+            }
+        }
+    }
+
+    if next < expected.len() {
+        problems.push(format!("Ids {} to {} are missing at the end",
+                              expected[next].id(), expected[expected.len() - 1].id()));
+    }
+
+    problems
+}
+
+/// Decodes the prepared output and checks that every id the manifest lists
+/// can be read back, complete and in order
+pub fn verify(config: &Config, expected: &[FrameRecord]) -> Result<(), Error> {
+    let decoded = decode_ids(config)?;
+    let problems = compare(expected, &decoded);
+
+    if problems.is_empty() {
+        println!("Verified {} frames in {}", decoded.len(), config.output);
+        return Ok(());
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    Err(VerificationFailed(config.output.clone(), problems.len()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use manifest::Segment;
+
+    fn expected(frames: u64) -> Vec<FrameRecord> {
+        (0..frames).map(|index| FrameRecord {
+            segment: Segment::Content,
+            clip: Some(0),
+            prefix: "f:".to_owned(),
+            index,
+            pts: None,
+            duration: None,
+            source_frame: None,
+            source_pts: None,
+            hash: String::new(),
+        }).collect()
+    }
+
+    fn decoded(ids: &[&str]) -> Vec<Option<String>> {
+        ids.iter().map(|id| if id.is_empty() { None } else { Some(id.to_string()) }).collect()
+    }
+
+    #[test]
+    fn matching_ids_have_no_problems() {
+        assert!(compare(&expected(3), &decoded(&["f:0", "f:1", "f:2"])).is_empty());
+    }
+
+    #[test]
+    fn missing_ids_are_reported_as_ranges() {
+        assert_eq!(compare(&expected(6), &decoded(&["f:0", "f:3", "f:4"])), vec![
+            "Expected 6 frames but decoded 3",
+            "Ids f:1 to f:2 are missing before frame 1",
+            "Ids f:5 to f:5 are missing at the end",
+        ]);
+    }
+
+    #[test]
+    fn unreadable_unknown_and_repeated_ids_are_reported() {
+        assert_eq!(compare(&expected(3), &decoded(&["f:0", "", "f:1", "f:1", "f:7", "f:2"])), vec![
+            "Expected 3 frames but decoded 6",
+            "Frame 1 is unreadable (expected f:1)",
+            "Frame 3 has id f:1 out of order or repeated",
+            "Frame 4 has unknown id f:7",
+        ]);
+    }
+
+    #[test]
+    fn unreadable_frames_past_the_end_expect_nothing() {
+        assert_eq!(compare(&expected(1), &decoded(&["f:0", ""])), vec![
+            "Expected 1 frames but decoded 2",
+            "Frame 1 is unreadable (expected -)",
+        ]);
+    }
+}
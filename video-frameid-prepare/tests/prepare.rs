@@ -0,0 +1,200 @@
+// Runs the video-frameid-prepare binary on generated inputs. Needs GStreamer
+// with the base, good and ugly plugins, and rsframeid built in the same
+// target directory.
+
+extern crate gstreamer as gst;
+extern crate serde_json;
+
+use gst::prelude::*;
+
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// Target directory holding the binary and the rsframeid plugin
+fn target_dir() -> PathBuf {
+    // Integration tests run from target/<profile>/deps
+    let mut dir = env::current_exe().unwrap();
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = target_dir().join("prepare-tests").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run_pipeline(description: &str) {
+    gst::init().unwrap();
+    run_until_eos(&gst::parse_launch(description).unwrap());
+}
+
+fn run_until_eos(pipeline: &gst::Element) {
+    pipeline.set_state(gst::State::Playing).into_result().unwrap();
+
+    let bus = pipeline.get_bus().unwrap();
+    while let Some(msg) = bus.timed_pop(gst::CLOCK_TIME_NONE) {
+        match msg.view() {
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Error(err) => panic!("{}", err.get_error()),
+            _ => (),
+        }
+    }
+    pipeline.set_state(gst::State::Null).into_result().unwrap();
+}
+
+/// Writes `frames` frames at 25 fps with a keyframe every `gop` frames
+fn make_input(dir: &PathBuf, frames: u32, gop: u32) -> String {
+    let path = dir.join("input.mp4").to_string_lossy().into_owned();
+    run_pipeline(&format!(
+        "videotestsrc num-buffers={} ! video/x-raw,width=320,height=240,framerate=25/1 ! \
+         x264enc key-int-max={} ! mp4mux ! filesink location={}",
+        frames, gop, path));
+    path
+}
+
+/// Writes `frames` raw frames in `format` at 25 fps
+fn make_raw_input(dir: &PathBuf, frames: u32, format: &str) -> String {
+    let path = dir.join("input.mkv").to_string_lossy().into_owned();
+    run_pipeline(&format!(
+        "videotestsrc num-buffers={} ! video/x-raw,format={},width=320,height=240,framerate=25/1 ! \
+         matroskamux ! filesink location={}",
+        frames, format, path));
+    path
+}
+
+/// Writes `frames` frames at 25 fps with a second of 48 kHz mono audio per
+/// 25 frames
+fn make_input_with_audio(dir: &PathBuf, frames: u32) -> String {
+    let path = dir.join("input.mkv").to_string_lossy().into_owned();
+    run_pipeline(&format!(
+        "matroskamux name=mux ! filesink location={} \
+         videotestsrc num-buffers={} ! video/x-raw,format=I420,width=320,height=240,framerate=25/1 ! mux. \
+         audiotestsrc samplesperbuffer=1920 num-buffers={} ! audio/x-raw,format=S16LE,rate=48000,channels=1 ! mux.",
+        path, frames, frames));
+    path
+}
+
+/// Number of audio samples in a matroska file with FLAC audio
+fn decoded_samples(path: &str) -> u64 {
+    gst::init().unwrap();
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc location={} ! matroskademux ! flacparse ! flacdec ! audioconvert ! \
+         audio/x-raw,format=S16LE,channels=1 ! fakesink name=sink", path)).unwrap();
+    let samples = Arc::new(Mutex::new(0u64));
+    let samples_clone = samples.clone();
+    let sink = pipeline.clone().dynamic_cast::<gst::Bin>().unwrap().get_by_name("sink").unwrap();
+    sink.get_static_pad("sink").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            *samples_clone.lock().unwrap() += buffer.get_size() as u64 / 2;
+        }
+        gst::PadProbeReturn::Ok
+    });
+    run_until_eos(&pipeline);
+
+    let samples = *samples.lock().unwrap();
+    samples
+}
+
+/// Prepares `input` into `output` and returns the JSON manifest
+fn prepare(input: &str, output: &str, args: &[&str]) -> serde_json::Value {
+    let status = Command::new(target_dir().join("video-frameid-prepare"))
+        .env("GST_PLUGIN_PATH", target_dir())
+        .args(args)
+        .arg("--progress=none")
+        .arg("--verify")
+        .arg(input)
+        .arg(output)
+        .status()
+        .unwrap();
+    assert!(status.success(), "video-frameid-prepare {:?} failed", args);
+
+    serde_json::from_reader(File::open(format!("{}.manifest.json", output)).unwrap()).unwrap()
+}
+
+/// Ids of the frames of `segment` in the manifest
+fn ids(manifest: &serde_json::Value, segment: &str) -> Vec<String> {
+    manifest["frames"].as_array().unwrap()
+        .iter()
+        .filter(|frame| frame["segment"] == segment)
+        .map(|frame| format!("{}{}", frame["prefix"].as_str().unwrap(), frame["index"]))
+        .collect()
+}
+
+fn expected_ids(prefix: &str, first: u64, frames: u64) -> Vec<String> {
+    (first..first + frames).map(|index| format!("{}{}", prefix, index)).collect()
+}
+
+#[test]
+fn trim_without_preroll() {
+    let dir = scratch_dir("trim_without_preroll");
+    let input = make_input(&dir, 50, 10);
+    let output = dir.join("output.mp4").to_string_lossy().into_owned();
+
+    let manifest = prepare(&input, &output, &["--preroll=0", "--postroll=0", "--start=23", "--end=33",
+                                              "--numbering=source"]);
+    assert_eq!(ids(&manifest, "content"), expected_ids("f:", 23, 10));
+    assert!(ids(&manifest, "pre").is_empty());
+}
+
+#[test]
+fn trim_after_preroll() {
+    let dir = scratch_dir("trim_after_preroll");
+    let input = make_input(&dir, 50, 10);
+    let output = dir.join("output.mp4").to_string_lossy().into_owned();
+
+    let manifest = prepare(&input, &output, &["--preroll=5", "--postroll=0", "--start=1s"]);
+    assert_eq!(ids(&manifest, "pre").len(), 5);
+    assert_eq!(ids(&manifest, "content"), expected_ids("f:", 0, 25));
+}
+
+#[test]
+fn chunks_join_into_one_sequence() {
+    let dir = scratch_dir("chunks_join_into_one_sequence");
+    let input = make_input(&dir, 100, 25);
+    let output = dir.join("output.mp4").to_string_lossy().into_owned();
+
+    let manifest = prepare(&input, &output, &["--preroll=5", "--postroll=5", "--chunk-duration=1", "--jobs=2"]);
+    assert_eq!(ids(&manifest, "pre").len(), 5);
+    assert_eq!(ids(&manifest, "content"), expected_ids("f:", 0, 100));
+    assert_eq!(ids(&manifest, "post").len(), 5);
+}
+
+#[test]
+fn high_bit_depth_is_kept() {
+    let dir = scratch_dir("high_bit_depth_is_kept");
+    let input = make_raw_input(&dir, 10, "P010_10LE");
+    let output = dir.join("output.mkv").to_string_lossy().into_owned();
+
+    let manifest = prepare(&input, &output, &["--preroll=2", "--postroll=2", "--profile=ffv1"]);
+    assert!(manifest["caps"].as_str().unwrap().contains("format=(string)I420_10LE"), "{}", manifest["caps"]);
+    assert_eq!(ids(&manifest, "content"), expected_ids("f:", 0, 10));
+}
+
+#[test]
+fn eight_bit_nv12_stays_eight_bit() {
+    let dir = scratch_dir("eight_bit_nv12_stays_eight_bit");
+    let input = make_raw_input(&dir, 10, "NV12");
+    let output = dir.join("output.mkv").to_string_lossy().into_owned();
+
+    let manifest = prepare(&input, &output, &["--preroll=2", "--postroll=2", "--profile=ffv1"]);
+    assert!(manifest["caps"].as_str().unwrap().contains("format=(string)I420,"), "{}", manifest["caps"]);
+}
+
+#[test]
+fn audio_filler_lasts_as_long_as_its_segment() {
+    let dir = scratch_dir("audio_filler_lasts_as_long_as_its_segment");
+    let input = make_input_with_audio(&dir, 25);
+    let output = dir.join("output.mkv").to_string_lossy().into_owned();
+
+    // Neither segment is a whole number of filler buffers
+    prepare(&input, &output, &["--preroll=13", "--postroll=7", "--profile=ffv1", "--audio-codec=flac"]);
+    assert_eq!(decoded_samples(&output), (13 + 25 + 7) * 1920);
+}